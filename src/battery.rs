@@ -0,0 +1,209 @@
+use crate::config::Config;
+use json::{object, JsonValue};
+
+///The voltage has to climb this far (V) above the warning level before a cap or cutoff is lifted. The pack
+///recovers a bit as soon as the load drops, that alone must not release it.
+const HYSTERESIS: f32 = 0.2;
+///Pack voltages below this share of the empty voltage mean nothing is wired to the pin (or the vehicle runs on
+///USB), the samples are ignored
+const MIN_PLAUSIBLE: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryConfig {
+    ///Caps the speed and stops the vehicle on a low battery
+    pub enabled: bool,
+    ///Battery voltage per volt on the ADC pin (voltage divider ratio)
+    pub divider: f32,
+    ///Weight of each new sample in the filtered voltage (0..1)
+    pub filter: f32,
+    ///Pack voltage at 100 % charge
+    pub full: f32,
+    ///Pack voltage at 0 % charge
+    pub empty: f32,
+    ///Below this voltage the max speed is capped, more the lower it gets
+    pub warning: f32,
+    ///Below this voltage the vehicle is stopped
+    pub cutoff: f32,
+    ///Max speed cap just above the cutoff
+    pub min_speed: i32,
+}
+
+impl Default for BatteryConfig {
+    ///2S LiPo behind a 30k/10k divider. Off until the divider is wired and the levels are set.
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            divider: 4.0,
+            filter: 0.05,
+            full: 8.4,
+            empty: 6.6,
+            warning: 7.0,
+            cutoff: 6.6,
+            min_speed: 40,
+        }
+    }
+}
+
+impl BatteryConfig {
+    pub fn is_valid(&self) -> bool {
+        self.divider > 0.0
+            && self.filter > 0.0
+            && self.filter <= 1.0
+            && self.full > self.empty
+            && self.warning > self.cutoff
+            && (0..=100).contains(&self.min_speed)
+    }
+}
+
+impl Config for BatteryConfig {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "enabled" => self.enabled,
+            "divider" => self.divider,
+            "filter" => self.filter,
+            "full" => self.full,
+            "empty" => self.empty,
+            "warning" => self.warning,
+            "cutoff" => self.cutoff,
+            "minSpeed" => self.min_speed,
+        }
+    }
+
+    ///Parses `{"enabled": true, "divider": 4.0, "filter": 0.05, "full": 8.4, "empty": 6.6, "warning": 7.0,
+    ///"cutoff": 6.6, "minSpeed": 40}`
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            enabled: data["enabled"].as_bool()?,
+            divider: data["divider"].as_f32()?,
+            filter: data["filter"].as_f32()?,
+            full: data["full"].as_f32()?,
+            empty: data["empty"].as_f32()?,
+            warning: data["warning"].as_f32()?,
+            cutoff: data["cutoff"].as_f32()?,
+            min_speed: data["minSpeed"].as_i32()?,
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
+
+///How the battery limits the vehicle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatteryState {
+    ///Off, no ADC or charged enough, no limit
+    Ok,
+    ///Below the warning level, the max speed is capped
+    Low,
+    ///Below the cutoff, the vehicle is stopped
+    Cutoff,
+}
+
+impl BatteryState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BatteryState::Ok => "ok",
+            BatteryState::Low => "low",
+            BatteryState::Cutoff => "cutoff",
+        }
+    }
+}
+
+///Filtered pack voltage with the speed cap that goes with it. The cap only tightens while the vehicle runs,
+///so the voltage recovering under a lighter load does not make it swing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryMonitor {
+    config: BatteryConfig,
+    voltage: Option<f32>,
+    state: BatteryState,
+    //Gällande tak för maxhastigheten
+    cap: Option<i32>,
+}
+
+impl BatteryMonitor {
+    pub fn new(config: BatteryConfig) -> Self {
+        Self {
+            config,
+            voltage: None,
+            state: BatteryState::Ok,
+            cap: None,
+        }
+    }
+
+    ///Takes effect from the next sample
+    pub fn set_config(&mut self, config: BatteryConfig) {
+        self.config = config;
+        self.cap = None;
+        self.state = BatteryState::Ok;
+    }
+
+    pub fn get_config(&self) -> BatteryConfig {
+        self.config
+    }
+
+    ///Adds a sample of the voltage (V) on the ADC pin
+    pub fn update(&mut self, pin_voltage: f32) {
+        let sample = pin_voltage * self.config.divider;
+        if sample < self.config.empty * MIN_PLAUSIBLE {
+            self.voltage = None;
+            self.state = BatteryState::Ok;
+            self.cap = None;
+            return;
+        }
+        let voltage = match self.voltage {
+            Some(last) => last + (sample - last) * self.config.filter,
+            None => sample,
+        };
+        self.voltage = Some(voltage);
+        if !self.config.enabled || voltage > self.config.warning + HYSTERESIS {
+            self.state = BatteryState::Ok;
+            self.cap = None;
+        } else if voltage <= self.config.cutoff || self.state == BatteryState::Cutoff {
+            self.state = BatteryState::Cutoff;
+            self.cap = Some(0);
+        } else if voltage < self.config.warning || self.state == BatteryState::Low {
+            //From full speed at the warning level down to the min speed at the cutoff
+            let share = ((voltage - self.config.cutoff)
+                / (self.config.warning - self.config.cutoff))
+                .clamp(0.0, 1.0);
+            let cap = self.config.min_speed
+                + ((100 - self.config.min_speed) as f32 * share).round() as i32;
+            self.state = BatteryState::Low;
+            self.cap = Some(self.cap.map_or(cap, |last| last.min(cap)));
+        }
+    }
+
+    ///Filtered pack voltage, None until the first sample
+    pub fn voltage(&self) -> Option<f32> {
+        self.voltage
+    }
+
+    ///Estimated state of charge (0..100 %), linear between the empty and full voltage
+    pub fn state_of_charge(&self) -> Option<f32> {
+        self.voltage.map(|voltage| {
+            ((voltage - self.config.empty) / (self.config.full - self.config.empty) * 100.0)
+                .clamp(0.0, 100.0)
+        })
+    }
+
+    ///Max speed cap for the battery level, None when there is no limit
+    pub fn speed_cap(&self) -> Option<i32> {
+        self.cap
+    }
+
+    pub fn state(&self) -> BatteryState {
+        self.state
+    }
+
+    ///State for the telemetry topic
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "voltage" => self.voltage,
+            "stateOfCharge" => self.state_of_charge(),
+            "state" => self.state.name(),
+            "speedCap" => self.cap,
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::config::Config;
+use json::{object, JsonValue};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+///Most points allowed in a calibration table
+pub const MAX_CALIBRATION_POINTS: usize = 16;
+
+//Plausible limits for a measured vehicle
+const LINEAR_RANGE: RangeInclusive<f32> = 0.01..=5.0;
+const ANGULAR_RANGE: RangeInclusive<f32> = 1.0..=3600.0;
+const TRACK_WIDTH_RANGE: RangeInclusive<f32> = 0.01..=2.0;
+///Longest time handed out (a day). Far beyond any program, so the estimate still turns it down, but safe
+///to build a Duration from and add to an Instant.
+const MAX_SECONDS: f32 = 86_400.0;
+
+///Measured speeds at one speed setting (percent)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationPoint {
+    pub speed: f32,
+    ///Meters per second driving straight
+    pub linear: f32,
+    ///Degrees per second when rotating in place
+    pub angular: f32,
+}
+
+///Timing model for the timed moves. Tells how far the vehicle gets at a given speed (percent), so programs
+///can be turned into drive times and estimated before they run. Speeds between the measured points are
+///interpolated linearly, outside them the nearest point is scaled proportionally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    ///Measured points, sorted by speed
+    pub points: Vec<CalibrationPoint>,
+    ///Distance between the left and right wheels (m), used for arcs
+    pub track_width: f32,
+}
+
+impl Default for Calibration {
+    ///Measured by hand: 2,8 s per meter and 2 s per 180 degrees at speed 75
+    fn default() -> Self {
+        Self {
+            points: vec![CalibrationPoint {
+                speed: 75.0,
+                linear: 1.0 / 2.8,
+                angular: 90.0,
+            }],
+            track_width: 0.15,
+        }
+    }
+}
+
+impl Calibration {
+    ///Meters per second at `speed`
+    pub fn linear_speed(&self, speed: f32) -> f32 {
+        self.lookup(speed, |point| point.linear)
+    }
+
+    ///Degrees per second at `speed`
+    pub fn angular_speed(&self, speed: f32) -> f32 {
+        self.lookup(speed, |point| point.angular)
+    }
+
+    ///Time to drive `meters` straight at `speed`
+    pub fn drive_time(&self, meters: f32, speed: f32) -> Duration {
+        seconds(meters / self.linear_speed(speed))
+    }
+
+    ///Time to rotate `degrees` in place at `speed`
+    pub fn rotate_time(&self, degrees: f32, speed: f32) -> Duration {
+        seconds(degrees.abs() / self.angular_speed(speed))
+    }
+
+    ///Inner side speed for an arc with `radius` meters when the outer side runs at `speed`
+    pub fn arc_inner_speed(&self, radius: f32, speed: f32) -> f32 {
+        let half_track = self.track_width / 2.0;
+        speed * (radius - half_track) / (radius + half_track)
+    }
+
+    ///Time to follow `degrees` of an arc with `radius` meters, outer side at `speed`
+    pub fn arc_time(&self, radius: f32, degrees: f32, speed: f32) -> Duration {
+        //The middle of the vehicle moves at the mean of the two sides
+        let center_speed = (speed + self.arc_inner_speed(radius, speed)) / 2.0;
+        self.drive_time(arc_length(radius, degrees), center_speed)
+    }
+
+    ///Value of `field` at `speed`
+    fn lookup(&self, speed: f32, field: impl Fn(&CalibrationPoint) -> f32) -> f32 {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0.0,
+        };
+        if speed <= first.speed {
+            return field(first) * speed / first.speed;
+        }
+        if speed >= last.speed {
+            return field(last) * speed / last.speed;
+        }
+        let i = self
+            .points
+            .windows(2)
+            .position(|w| speed <= w[1].speed)
+            .unwrap_or(0);
+        let (low, high) = (&self.points[i], &self.points[i + 1]);
+        let part = (speed - low.speed) / (high.speed - low.speed);
+        field(low) + (field(high) - field(low)) * part
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.points.is_empty()
+            && self.points.len() <= MAX_CALIBRATION_POINTS
+            && TRACK_WIDTH_RANGE.contains(&self.track_width)
+            && self.points.iter().all(|p| {
+                p.speed > 0.0
+                    && p.speed <= 100.0
+                    && LINEAR_RANGE.contains(&p.linear)
+                    && ANGULAR_RANGE.contains(&p.angular)
+            })
+            && self.points.windows(2).all(|w| w[0].speed < w[1].speed)
+    }
+}
+
+impl Config for Calibration {
+    fn to_json(&self) -> JsonValue {
+        let points: Vec<JsonValue> = self
+            .points
+            .iter()
+            .map(|p| object! { "speed" => p.speed, "linear" => p.linear, "angular" => p.angular })
+            .collect();
+        object! {
+            "points" => points,
+            "trackWidth" => self.track_width,
+        }
+    }
+
+    ///Parses `{"points": [{"speed": 75, "linear": 0.36, "angular": 90}, ..], "trackWidth": 0.15}`.
+    ///The points may come in any order.
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let mut points = Vec::new();
+        for point in data["points"].members() {
+            points.push(CalibrationPoint {
+                speed: point["speed"].as_f32()?,
+                linear: point["linear"].as_f32()?,
+                angular: point["angular"].as_f32()?,
+            });
+        }
+        points.sort_by(|a, b| a.speed.total_cmp(&b.speed));
+        let calibration = Self {
+            points,
+            track_width: data["trackWidth"]
+                .as_f32()
+                .unwrap_or(Self::default().track_width),
+        };
+        if calibration.is_valid() {
+            Some(calibration)
+        } else {
+            None
+        }
+    }
+}
+
+///Distance along an arc with `radius` meters
+pub fn arc_length(radius: f32, degrees: f32) -> f32 {
+    radius * degrees.abs().to_radians()
+}
+
+///Seconds as a duration, zero for negative or NaN times and at most `MAX_SECONDS`
+fn seconds(s: f32) -> Duration {
+    if s > 0.0 {
+        Duration::from_secs_f32(s.min(MAX_SECONDS))
+    } else {
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(speed: f32, linear: f32, angular: f32) -> CalibrationPoint {
+        CalibrationPoint {
+            speed,
+            linear,
+            angular,
+        }
+    }
+
+    ///0,2 m/s at speed 40 and 0,5 m/s at speed 100
+    fn two_points() -> Calibration {
+        Calibration {
+            points: vec![point(40.0, 0.2, 60.0), point(100.0, 0.5, 180.0)],
+            track_width: 0.2,
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn looks_up_measured_points() {
+        let calibration = two_points();
+        assert!(close(calibration.linear_speed(40.0), 0.2));
+        assert!(close(calibration.angular_speed(100.0), 180.0));
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let calibration = two_points();
+        assert!(close(calibration.linear_speed(70.0), 0.35));
+        assert!(close(calibration.angular_speed(55.0), 90.0));
+    }
+
+    #[test]
+    fn scales_outside_the_points() {
+        let calibration = two_points();
+        assert!(close(calibration.linear_speed(20.0), 0.1));
+        assert!(close(calibration.angular_speed(10.0), 15.0));
+        //Above the last point, from the last point alone
+        assert!(close(calibration.linear_speed(120.0), 0.6));
+    }
+
+    #[test]
+    fn times_moves() {
+        let calibration = two_points();
+        assert_eq!(calibration.drive_time(1.0, 100.0), Duration::from_secs(2));
+        assert_eq!(
+            calibration.rotate_time(-90.0, 40.0),
+            Duration::from_millis(1500)
+        );
+        //Track 0,2 m, a 0,3 m radius gives the inner side half the outer speed
+        assert!(close(calibration.arc_inner_speed(0.3, 100.0), 50.0));
+        assert!(close(arc_length(0.5, -180.0), std::f32::consts::FRAC_PI_2));
+    }
+
+    #[test]
+    fn bounds_times() {
+        let calibration = two_points();
+        assert_eq!(
+            calibration.drive_time(1e30, 100.0),
+            Duration::from_secs_f32(MAX_SECONDS)
+        );
+        assert_eq!(calibration.drive_time(-1.0, 100.0), Duration::ZERO);
+        assert_eq!(calibration.drive_time(f32::NAN, 100.0), Duration::ZERO);
+        //Nothing to divide by at speed 0
+        assert_eq!(calibration.drive_time(0.0, 0.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_implausible_models() {
+        assert!(two_points().is_valid());
+        for points in [
+            vec![],
+            vec![point(40.0, 0.0, 60.0)],
+            vec![point(40.0, 6.0, 60.0)],
+            vec![point(40.0, 0.2, 0.5)],
+            vec![point(40.0, 0.2, f32::INFINITY)],
+            vec![point(f32::NAN, 0.2, 60.0)],
+            vec![point(101.0, 0.2, 60.0)],
+            vec![point(70.0, 0.2, 60.0), point(40.0, 0.3, 90.0)],
+            vec![point(50.0, 0.2, 60.0); MAX_CALIBRATION_POINTS + 1],
+        ] {
+            let calibration = Calibration {
+                points,
+                track_width: 0.15,
+            };
+            assert!(!calibration.is_valid(), "{:?}", calibration.points);
+        }
+        let wide = Calibration {
+            track_width: 3.0,
+            ..two_points()
+        };
+        assert!(!wide.is_valid());
+    }
+
+    #[test]
+    fn parses_points_in_any_order() {
+        let data = json::parse(
+            r#"{"points": [{"speed": 100, "linear": 0.5, "angular": 180}, {"speed": 40, "linear": 0.2, "angular": 60}]}"#,
+        )
+        .unwrap();
+        let calibration = Calibration::from_json(&data).unwrap();
+        assert_eq!(calibration.points, two_points().points);
+        assert_eq!(calibration.track_width, Calibration::default().track_width);
+        assert_eq!(
+            Calibration::from_json(&calibration.to_json()),
+            Some(calibration)
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use crate::calibration::{Calibration, CalibrationPoint, MAX_CALIBRATION_POINTS};
+use crate::program::{Instruction, Program};
+use json::{object, JsonValue};
+use std::time::Duration;
+
+///Speeds measured when none are given
+const DEFAULT_SPEEDS: [f32; 3] = [40.0, 70.0, 100.0];
+const DEFAULT_RUN_TIME: Duration = Duration::from_millis(2000);
+const MIN_RUN_TIME: Duration = Duration::from_millis(500);
+const MAX_RUN_TIME: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrialKind {
+    ///Straight forward, the operator measures the distance (m)
+    Straight,
+    ///Rotation in place, the operator measures the angle (degrees)
+    Spin,
+}
+
+impl TrialKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrialKind::Straight => "straight",
+            TrialKind::Spin => "spin",
+        }
+    }
+
+    ///What the operator should measure
+    pub fn measure(&self) -> &'static str {
+        match self {
+            TrialKind::Straight => "distance",
+            TrialKind::Spin => "angle",
+        }
+    }
+}
+
+///One timed run of the calibration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trial {
+    pub kind: TrialKind,
+    ///Requested speed
+    pub speed: f32,
+    pub duration: Duration,
+    ///Speed the run actually had after the max speed cap, set when it has been run
+    pub actual_speed: Option<f32>,
+    ///Distance or angle reported by the operator
+    pub measured: Option<f32>,
+}
+
+impl Trial {
+    pub fn to_json(&self, index: usize) -> JsonValue {
+        let mut data = object! {
+            "index" => index,
+            "kind" => self.kind.name(),
+            "speed" => self.speed,
+            "duration" => self.duration.as_millis() as u64,
+            "measure" => self.kind.measure(),
+        };
+        if let Some(speed) = self.actual_speed {
+            data["actualSpeed"] = speed.into();
+        }
+        if let Some(value) = self.measured {
+            data["measured"] = value.into();
+        }
+        data
+    }
+}
+
+///Guided calibration: a straight run and a spin at each speed, each run for a fixed time. The operator
+///reports how far the vehicle got and the model is fitted from distance (or angle) per second.
+///Timed moves ramp up and down the same way, so fitting against the commanded time gives the right drive
+///times without measuring the ramps separately.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationSession {
+    trials: Vec<Trial>,
+    ///Trial waiting for its measurement
+    last_run: Option<usize>,
+    track_width: f32,
+}
+
+impl CalibrationSession {
+    pub fn new(speeds: &[f32], duration: Duration, track_width: f32) -> Result<Self, &'static str> {
+        if speeds.is_empty() || speeds.len() > MAX_CALIBRATION_POINTS {
+            return Err("Ange 1 - 16 hastigheter");
+        }
+        if speeds.iter().any(|s| s.is_nan() || *s <= 0.0 || *s > 100.0) {
+            return Err("Hastigheterna ska vara 1 - 100");
+        }
+        if duration < MIN_RUN_TIME || duration > MAX_RUN_TIME {
+            return Err("Körtiden ska vara 500 - 10000 ms");
+        }
+        if track_width.is_nan() || track_width <= 0.0 {
+            return Err("Spårvidden ska vara större än noll");
+        }
+        let trials = speeds
+            .iter()
+            .flat_map(|&speed| {
+                [TrialKind::Straight, TrialKind::Spin].map(|kind| Trial {
+                    kind,
+                    speed,
+                    duration,
+                    actual_speed: None,
+                    measured: None,
+                })
+            })
+            .collect();
+        Ok(Self {
+            trials,
+            last_run: None,
+            track_width,
+        })
+    }
+
+    ///Parses `{"speeds": [40, 70, 100], "duration": 2000, "trackWidth": 0.15}`, every field is optional.
+    ///The track width is kept from `current` when not given.
+    pub fn from_json(data: &JsonValue, current: &Calibration) -> Result<Self, &'static str> {
+        let mut speeds = Vec::new();
+        for speed in data["speeds"].members() {
+            speeds.push(speed.as_f32().ok_or("Hastigheterna ska vara tal")?);
+        }
+        if speeds.is_empty() {
+            speeds.extend_from_slice(&DEFAULT_SPEEDS);
+        }
+        let duration = data["duration"]
+            .as_u64()
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_RUN_TIME);
+        let track_width = data["trackWidth"].as_f32().unwrap_or(current.track_width);
+        Self::new(&speeds, duration, track_width)
+    }
+
+    pub fn trials(&self) -> &[Trial] {
+        &self.trials
+    }
+
+    ///First trial without a measurement
+    pub fn next(&self) -> Option<usize> {
+        self.trials.iter().position(|t| t.measured.is_none())
+    }
+
+    ///Program running the next trial, with the trial index and the speed it runs at. `cap` gives the speed a
+    ///move asking for a speed will run at, and the move is sized with `calibration` so it runs for exactly the
+    ///trial time. Nothing is recorded until `mark_run`, the program may still be turned down.
+    pub fn plan_next(
+        &self,
+        calibration: &Calibration,
+        cap: impl Fn(f32) -> f32,
+    ) -> Option<(usize, f32, Program)> {
+        let index = self.next()?;
+        let trial = &self.trials[index];
+        let speed = cap(trial.speed);
+        let seconds = trial.duration.as_secs_f32();
+        let instruction = match trial.kind {
+            TrialKind::Straight => Instruction::Forward {
+                meters: calibration.linear_speed(speed) * seconds,
+                speed: Some(speed),
+            },
+            TrialKind::Spin => Instruction::RotateLeft {
+                degrees: calibration.angular_speed(speed) * seconds,
+                speed: Some(speed),
+            },
+        };
+        Some((
+            index,
+            speed,
+            Program {
+                instructions: vec![instruction],
+            },
+        ))
+    }
+
+    ///Records that the planned trial `index` has started at `speed`, the next measurement belongs to it
+    pub fn mark_run(&mut self, index: usize, speed: f32) {
+        self.trials[index].actual_speed = Some(speed);
+        self.last_run = Some(index);
+    }
+
+    ///Records the distance (m) or angle (degrees) of the last run trial
+    pub fn measure(&mut self, value: f32) -> Result<usize, &'static str> {
+        let index = self
+            .last_run
+            .take()
+            .ok_or("Ingen körning väntar på mätning")?;
+        if !(value.is_finite() && value > 0.0) {
+            self.last_run = Some(index);
+            return Err("Mätvärdet ska vara större än noll");
+        }
+        self.trials[index].measured = Some(value);
+        Ok(index)
+    }
+
+    ///Model fitted from the measured trials. Every speed with both a straight run and a spin gives one point,
+    ///trials that were capped to the same speed are averaged.
+    pub fn fit(&self) -> Option<Calibration> {
+        //(speed, linear sum, linear count, angular sum, angular count)
+        let mut sums: Vec<(f32, f32, u32, f32, u32)> = Vec::new();
+        for trial in &self.trials {
+            let (speed, value) = match (trial.actual_speed, trial.measured) {
+                (Some(speed), Some(value)) => (speed, value / trial.duration.as_secs_f32()),
+                _ => continue,
+            };
+            let i = match sums.iter().position(|s| s.0 == speed) {
+                Some(i) => i,
+                None => {
+                    sums.push((speed, 0.0, 0, 0.0, 0));
+                    sums.len() - 1
+                }
+            };
+            match trial.kind {
+                TrialKind::Straight => {
+                    sums[i].1 += value;
+                    sums[i].2 += 1;
+                }
+                TrialKind::Spin => {
+                    sums[i].3 += value;
+                    sums[i].4 += 1;
+                }
+            }
+        }
+        let mut points: Vec<CalibrationPoint> = sums
+            .iter()
+            .filter(|s| s.2 > 0 && s.4 > 0)
+            .map(|s| CalibrationPoint {
+                speed: s.0,
+                linear: s.1 / s.2 as f32,
+                angular: s.3 / s.4 as f32,
+            })
+            .collect();
+        points.sort_by(|a, b| a.speed.total_cmp(&b.speed));
+        let calibration = Calibration {
+            points,
+            track_width: self.track_width,
+        };
+        if calibration.is_valid() {
+            Some(calibration)
+        } else {
+            None
+        }
+    }
+
+    ///Every trial, for the progress and result reports
+    pub fn to_json(&self) -> JsonValue {
+        let trials: Vec<JsonValue> = self
+            .trials
+            .iter()
+            .enumerate()
+            .map(|(i, trial)| trial.to_json(i))
+            .collect();
+        object! { "trials" => trials }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Runs the next trial at the speed `cap` gives and reports `value` for it
+    fn run_and_measure(session: &mut CalibrationSession, cap: impl Fn(f32) -> f32, value: f32) {
+        let (index, speed, _) = session.plan_next(&Calibration::default(), cap).unwrap();
+        session.mark_run(index, speed);
+        session.measure(value).unwrap();
+    }
+
+    #[test]
+    fn checks_the_session() {
+        let second = Duration::from_secs(1);
+        assert!(CalibrationSession::new(&[], second, 0.15).is_err());
+        assert!(CalibrationSession::new(&[0.0], second, 0.15).is_err());
+        assert!(CalibrationSession::new(&[f32::NAN], second, 0.15).is_err());
+        assert!(CalibrationSession::new(&[50.0; 17], second, 0.15).is_err());
+        assert!(CalibrationSession::new(&[50.0], Duration::from_millis(100), 0.15).is_err());
+        assert!(CalibrationSession::new(&[50.0], second, 0.0).is_err());
+        let session = CalibrationSession::new(&[50.0], second, 0.15).unwrap();
+        assert_eq!(session.trials().len(), 2);
+    }
+
+    #[test]
+    fn defaults_from_json() {
+        let current = Calibration {
+            track_width: 0.2,
+            ..Calibration::default()
+        };
+        let session = CalibrationSession::from_json(&json::parse("{}").unwrap(), &current).unwrap();
+        assert_eq!(session.trials().len(), 2 * DEFAULT_SPEEDS.len());
+        assert_eq!(session.trials()[0].duration, DEFAULT_RUN_TIME);
+        assert_eq!(session.track_width, 0.2);
+    }
+
+    #[test]
+    fn planning_records_nothing() {
+        let mut session = CalibrationSession::new(&[50.0], Duration::from_secs(2), 0.15).unwrap();
+        let calibration = Calibration::default();
+        let (index, speed, program) = session.plan_next(&calibration, |s| s.min(40.0)).unwrap();
+        assert_eq!((index, speed), (0, 40.0));
+        //Sized to run for the trial time at the capped speed
+        assert_eq!(
+            program.instructions,
+            [Instruction::Forward {
+                meters: calibration.linear_speed(40.0) * 2.0,
+                speed: Some(40.0),
+            }]
+        );
+        assert_eq!(session.trials()[0].actual_speed, None);
+        assert!(session.measure(1.0).is_err());
+    }
+
+    #[test]
+    fn measures_the_trial_that_was_run() {
+        let mut session = CalibrationSession::new(&[50.0], Duration::from_secs(2), 0.15).unwrap();
+        let (index, speed, _) = session.plan_next(&Calibration::default(), |s| s).unwrap();
+        session.mark_run(index, speed);
+        //A bad value keeps the trial waiting
+        assert!(session.measure(-1.0).is_err());
+        assert_eq!(session.measure(0.8), Ok(0));
+        assert!(session.measure(0.8).is_err());
+        assert_eq!(session.next(), Some(1));
+    }
+
+    #[test]
+    fn fits_a_point_per_speed() {
+        let mut session =
+            CalibrationSession::new(&[40.0, 80.0], Duration::from_secs(2), 0.15).unwrap();
+        assert_eq!(session.fit(), None);
+        run_and_measure(&mut session, |s| s, 0.4);
+        run_and_measure(&mut session, |s| s, 120.0);
+        run_and_measure(&mut session, |s| s, 1.0);
+        //A speed with only its straight run gives no point
+        let fitted = session.fit().unwrap();
+        assert_eq!(fitted.points.len(), 1);
+        run_and_measure(&mut session, |s| s, 360.0);
+        let fitted = session.fit().unwrap();
+        assert_eq!(
+            fitted.points,
+            [
+                CalibrationPoint {
+                    speed: 40.0,
+                    linear: 0.2,
+                    angular: 60.0
+                },
+                CalibrationPoint {
+                    speed: 80.0,
+                    linear: 0.5,
+                    angular: 180.0
+                },
+            ]
+        );
+        assert_eq!(fitted.track_width, 0.15);
+    }
+
+    #[test]
+    fn averages_trials_capped_to_the_same_speed() {
+        let mut session =
+            CalibrationSession::new(&[80.0, 100.0], Duration::from_secs(1), 0.15).unwrap();
+        let cap = |s: f32| s.min(60.0);
+        for value in [0.3, 90.0, 0.5, 110.0] {
+            run_and_measure(&mut session, cap, value);
+        }
+        let fitted = session.fit().unwrap();
+        assert_eq!(
+            fitted.points,
+            [CalibrationPoint {
+                speed: 60.0,
+                linear: 0.4,
+                angular: 100.0
+            }]
+        );
+    }
+
+    #[test]
+    fn turns_down_implausible_measurements() {
+        let mut session = CalibrationSession::new(&[50.0], Duration::from_secs(1), 0.15).unwrap();
+        //100 m in a second
+        run_and_measure(&mut session, |s| s, 100.0);
+        run_and_measure(&mut session, |s| s, 90.0);
+        assert_eq!(session.fit(), None);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config::Config;
+use crate::imu::ImuSample;
+use json::{object, JsonValue};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollisionConfig {
+    ///Latches the emergency stop on an impact when an IMU is fitted
+    pub enabled: bool,
+    ///Horizontal acceleration (g) that counts as an impact
+    pub threshold: f32,
+    ///The acceleration has to stay above the threshold this long, so single bumps are let through
+    pub debounce: Duration,
+}
+
+impl Default for CollisionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 2.0,
+            debounce: Duration::from_millis(20),
+        }
+    }
+}
+
+impl CollisionConfig {
+    pub fn is_valid(&self) -> bool {
+        self.threshold > 0.0
+    }
+}
+
+impl Config for CollisionConfig {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "enabled" => self.enabled,
+            "threshold" => self.threshold,
+            "debounce" => self.debounce.as_millis() as u64,
+        }
+    }
+
+    ///Parses `{"enabled": true, "threshold": 2.0, "debounce": 20}`, debounce in milliseconds
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            enabled: data["enabled"].as_bool()?,
+            threshold: data["threshold"].as_f32()?,
+            debounce: Duration::from_millis(data["debounce"].as_u64()?),
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
+
+///Looks for impacts in the accelerometer samples. Only x and y are used, gravity and bumps in the floor
+///show up on z.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollisionDetector {
+    config: CollisionConfig,
+    //Första provet över tröskeln i pågående topp
+    above_since: Option<Instant>,
+    //Högsta acceleration i pågående topp
+    peak: f32,
+}
+
+impl CollisionDetector {
+    pub fn new(config: CollisionConfig) -> Self {
+        Self {
+            config,
+            above_since: None,
+            peak: 0.0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: CollisionConfig) {
+        self.config = config;
+        self.above_since = None;
+    }
+
+    pub fn get_config(&self) -> CollisionConfig {
+        self.config
+    }
+
+    ///Adds a sample. Returns the peak acceleration (g) once a spike has lasted the debounce time.
+    pub fn update(&mut self, sample: &ImuSample) -> Option<f32> {
+        let [x, y, _] = sample.accel;
+        let accel = x.hypot(y);
+        if !self.config.enabled || accel < self.config.threshold {
+            self.above_since = None;
+            return None;
+        }
+        let now = Instant::now();
+        let since = match self.above_since {
+            Some(since) => {
+                self.peak = self.peak.max(accel);
+                since
+            }
+            None => {
+                self.peak = accel;
+                *self.above_since.insert(now)
+            }
+        };
+        if now.duration_since(since) >= self.config.debounce {
+            self.above_since = None;
+            Some(self.peak)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use json::JsonValue;
+
+///A vehicle config that is sent over MQTT and stored in NVS as JSON
+pub trait Config: Sized {
+    fn to_json(&self) -> JsonValue;
+
+    ///Parses a config, None if a field is missing or a value is out of range
+    fn from_json(data: &JsonValue) -> Option<Self>;
+}
@@ -0,0 +1,191 @@
+use crate::config::Config;
+use crate::motordriver::Wheel;
+use json::{object, JsonValue};
+
+///Most points allowed in a lookup table curve
+pub const MAX_TABLE_POINTS: usize = 16;
+
+///How speed 0-100 is spread between the start duty and the max duty of a curve
+#[derive(Debug, Clone, PartialEq)]
+pub enum CurveShape {
+    Linear,
+    ///(speed/100)^exponent. Exponents above 1 give finer control at low speed
+    Exponential(f32),
+    ///Percent (0-100) of the duty span at evenly spaced speeds from 0 to 100, interpolated linearly
+    Table(Vec<u8>),
+}
+
+///Maps speed in percent to motor duty. Speed 0 always gives duty 0 (stopped), everything above starts at `min`
+///so the motor does not stall.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DutyCurve {
+    ///Lowest duty where the motor starts to turn
+    pub min: u8,
+    ///Duty at speed 100
+    pub max: u8,
+    pub shape: CurveShape,
+}
+
+///Current hardware has a minimum of 190 and max 255, translated linearly
+impl Default for DutyCurve {
+    fn default() -> Self {
+        Self {
+            min: 190,
+            max: 255,
+            shape: CurveShape::Linear,
+        }
+    }
+}
+
+impl DutyCurve {
+    ///Duty for a speed of 0-100 percent
+    pub fn duty(&self, speed: f32) -> u8 {
+        if speed <= 0.0 {
+            return 0;
+        }
+        let speed = speed.min(100.0) / 100.0;
+        let fraction = match &self.shape {
+            CurveShape::Linear => speed,
+            CurveShape::Exponential(exponent) => speed.powf(*exponent),
+            CurveShape::Table(points) => interpolate(points, speed),
+        };
+        let span = (self.max - self.min) as f32;
+        (self.min as f32 + span * fraction) as u8
+    }
+
+    pub fn is_valid(&self) -> bool {
+        if self.min > self.max {
+            return false;
+        }
+        match &self.shape {
+            CurveShape::Linear => true,
+            CurveShape::Exponential(exponent) => *exponent > 0.0,
+            CurveShape::Table(points) => {
+                !points.is_empty()
+                    && points.len() <= MAX_TABLE_POINTS
+                    && points.iter().all(|p| *p <= 100)
+                    && points.windows(2).all(|w| w[0] <= w[1])
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut data = object! {
+            "min" => self.min,
+            "max" => self.max,
+        };
+        match &self.shape {
+            CurveShape::Linear => data["shape"] = "linear".into(),
+            CurveShape::Exponential(exponent) => {
+                data["shape"] = "exponential".into();
+                data["exponent"] = (*exponent).into();
+            }
+            CurveShape::Table(points) => {
+                data["shape"] = "table".into();
+                data["table"] = points.clone().into();
+            }
+        }
+        data
+    }
+
+    ///Parses `{"min": 190, "max": 255, "shape": "linear" | "exponential" | "table", "exponent": 2.0, "table": [..]}`
+    pub fn from_json(data: &JsonValue) -> Option<Self> {
+        let shape = match data["shape"].as_str().unwrap_or("linear") {
+            "linear" => CurveShape::Linear,
+            "exponential" => CurveShape::Exponential(data["exponent"].as_f32()?),
+            "table" => {
+                let mut points = Vec::new();
+                for point in data["table"].members() {
+                    points.push(point.as_u8()?);
+                }
+                CurveShape::Table(points)
+            }
+            _ => return None,
+        };
+        let curve = Self {
+            min: data["min"].as_u8()?,
+            max: data["max"].as_u8()?,
+            shape,
+        };
+        if curve.is_valid() {
+            Some(curve)
+        } else {
+            None
+        }
+    }
+}
+
+///Linear interpolation between evenly spaced table points (percent) for speed 0.0-1.0
+fn interpolate(points: &[u8], speed: f32) -> f32 {
+    if points.len() == 1 {
+        return points[0] as f32 / 100.0;
+    }
+    let position = speed * (points.len() - 1) as f32;
+    let index = (position as usize).min(points.len() - 2);
+    let part = position - index as f32;
+    let low = points[index] as f32;
+    let high = points[index + 1] as f32;
+    (low + (high - low) * part) / 100.0
+}
+
+///Forward and backward curve for one wheel
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WheelCurves {
+    pub forward: DutyCurve,
+    pub backward: DutyCurve,
+}
+
+///Duty curves for every wheel, indexed by `Wheel::index`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DutyCurves(pub [WheelCurves; 4]);
+
+impl DutyCurves {
+    ///Signed duty for a wheel at signed speed -100..100
+    pub fn duty(&self, wheel: Wheel, speed: f32) -> i16 {
+        let curves = &self.0[wheel.index()];
+        if speed >= 0.0 {
+            curves.forward.duty(speed) as i16
+        } else {
+            -(curves.backward.duty(-speed) as i16)
+        }
+    }
+
+    ///Replaces the curve for the given wheels and directions
+    pub fn set(&mut self, wheels: &[Wheel], forward: bool, backward: bool, curve: &DutyCurve) {
+        for wheel in wheels {
+            let curves = &mut self.0[wheel.index()];
+            if forward {
+                curves.forward = curve.clone();
+            }
+            if backward {
+                curves.backward = curve.clone();
+            }
+        }
+    }
+}
+
+impl Config for DutyCurves {
+    fn to_json(&self) -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for wheel in Wheel::ALL {
+            let curves = &self.0[wheel.index()];
+            data[wheel.name()] = object! {
+                "forward" => curves.forward.to_json(),
+                "backward" => curves.backward.to_json(),
+            };
+        }
+        data
+    }
+
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let mut curves = Self::default();
+        for wheel in Wheel::ALL {
+            let entry = &data[wheel.name()];
+            curves.0[wheel.index()] = WheelCurves {
+                forward: DutyCurve::from_json(&entry["forward"])?,
+                backward: DutyCurve::from_json(&entry["backward"])?,
+            };
+        }
+        Some(curves)
+    }
+}
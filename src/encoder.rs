@@ -0,0 +1,203 @@
+use std::convert::Infallible;
+use std::fmt;
+
+///Encoder pulses per wheel since the last read, indexed by `Wheel::index`. None for wheels without an encoder.
+///Pulses are signed, positive when the wheel turns forward.
+pub type WheelPulses = [Option<i32>; 4];
+
+///Interface for wheel encoder inputs (the PCNT pulse counter on the device, a simulation on the host).
+///The control loop reads it every tick and hands the pulses to the vehicle controller.
+pub trait Encoder {
+    type Error: fmt::Display;
+
+    ///Pulses counted on every wheel since the last call
+    fn take_pulses(&mut self) -> Result<WheelPulses, Self::Error>;
+
+    ///Wheels that have an encoder, indexed by `Wheel::index`
+    fn fitted(&self) -> [bool; 4];
+}
+
+///For boards without encoders, every wheel runs open loop
+pub struct NoEncoder;
+
+impl Encoder for NoEncoder {
+    type Error = Infallible;
+
+    fn take_pulses(&mut self) -> Result<WheelPulses, Infallible> {
+        Ok([None; 4])
+    }
+
+    fn fitted(&self) -> [bool; 4] {
+        [false; 4]
+    }
+}
+
+#[cfg(any(esp32, esp32s2, esp32s3))]
+pub use self::pcnt::PcntEncoder;
+
+///Quadrature encoders on the PCNT pulse counter. The ESP32-C3 has no PCNT, so there the wheels run open loop.
+#[cfg(any(esp32, esp32s2, esp32s3))]
+mod pcnt {
+    use super::{Encoder, WheelPulses};
+    use crate::motordriver::Wheel;
+    use esp_idf_hal::gpio::{AnyInputPin, InputPin};
+    use esp_idf_hal::pcnt::{
+        Pcnt, PcntChannel, PcntChannelConfig, PcntControlMode, PcntCountMode, PcntDriver, PinIndex,
+    };
+    use esp_idf_hal::peripheral::Peripheral;
+    use esp_idf_sys::EspError;
+
+    ///The counter goes back to zero when it reaches this value in either direction
+    const COUNTER_LIMIT: i16 = i16::MAX;
+    ///Glitch filter in APB clock cycles (12,5 ns)
+    const FILTER: u16 = 100;
+
+    struct Unit {
+        driver: PcntDriver<'static>,
+        last: i16,
+    }
+
+    ///One PCNT unit per wheel with an encoder
+    #[derive(Default)]
+    pub struct PcntEncoder {
+        units: [Option<Unit>; 4],
+    }
+
+    impl PcntEncoder {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        ///Counts the quadrature encoder on `a` and `b` for `wheel`. Swap the pins if the wheel counts backwards.
+        pub fn add_wheel<PCNT: Pcnt>(
+            &mut self,
+            wheel: Wheel,
+            pcnt: impl Peripheral<P = PCNT> + 'static,
+            a: impl Peripheral<P = impl InputPin> + 'static,
+            b: impl Peripheral<P = impl InputPin> + 'static,
+        ) -> Result<(), EspError> {
+            let mut driver = PcntDriver::new(
+                pcnt,
+                Some(a),
+                Some(b),
+                Option::<AnyInputPin>::None,
+                Option::<AnyInputPin>::None,
+            )?;
+            //Both edges of A, direction from the level of B
+            driver.channel_config(
+                PcntChannel::Channel0,
+                PinIndex::Pin0,
+                PinIndex::Pin1,
+                &PcntChannelConfig {
+                    lctrl_mode: PcntControlMode::Reverse,
+                    hctrl_mode: PcntControlMode::Keep,
+                    pos_mode: PcntCountMode::Decrement,
+                    neg_mode: PcntCountMode::Increment,
+                    counter_h_lim: COUNTER_LIMIT,
+                    counter_l_lim: -COUNTER_LIMIT,
+                },
+            )?;
+            driver.set_filter_value(FILTER)?;
+            driver.filter_enable()?;
+            driver.counter_pause()?;
+            driver.counter_clear()?;
+            driver.counter_resume()?;
+            self.units[wheel.index()] = Some(Unit { driver, last: 0 });
+            Ok(())
+        }
+    }
+
+    impl Encoder for PcntEncoder {
+        type Error = EspError;
+
+        fn take_pulses(&mut self) -> Result<WheelPulses, EspError> {
+            let mut pulses: WheelPulses = [None; 4];
+            for (i, unit) in self.units.iter_mut().enumerate() {
+                if let Some(unit) = unit {
+                    let count = unit.driver.get_counter_value()?;
+                    let mut delta = count as i32 - unit.last as i32;
+                    //The counter restarted from zero at the limit since the last read
+                    if delta > COUNTER_LIMIT as i32 / 2 {
+                        delta -= COUNTER_LIMIT as i32;
+                    } else if delta < -(COUNTER_LIMIT as i32 / 2) {
+                        delta += COUNTER_LIMIT as i32;
+                    }
+                    unit.last = count;
+                    pulses[i] = Some(delta);
+                }
+            }
+            Ok(pulses)
+        }
+
+        fn fitted(&self) -> [bool; 4] {
+            self.units.each_ref().map(Option::is_some)
+        }
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub use self::simulated::SimulatedEncoder;
+
+///Encoders for running the controller on the host, without wheels
+#[cfg(not(target_os = "espidf"))]
+mod simulated {
+    use super::{Encoder, WheelPulses};
+    use std::convert::Infallible;
+    use std::time::Instant;
+
+    ///Turns wheel speeds set by the simulation into the pulses real encoders would count
+    pub struct SimulatedEncoder {
+        pulses_per_meter: f32,
+        ///m/s per wheel, None for wheels without an encoder
+        speeds: [Option<f32>; 4],
+        //Pulses not yet whole
+        remainder: [f32; 4],
+        last: Instant,
+    }
+
+    impl SimulatedEncoder {
+        pub fn new(pulses_per_meter: f32) -> Self {
+            Self {
+                pulses_per_meter,
+                speeds: [Some(0.0); 4],
+                remainder: [0.0; 4],
+                last: Instant::now(),
+            }
+        }
+
+        ///Wheel speeds (m/s) from now on
+        pub fn set_speeds(&mut self, speeds: [Option<f32>; 4]) {
+            self.speeds = speeds;
+        }
+
+        ///Pulses counted up to `now`, for stepping the simulation without waiting for the clock
+        pub fn take_pulses_at(&mut self, now: Instant) -> WheelPulses {
+            let dt = now.saturating_duration_since(self.last).as_secs_f32();
+            self.last = now;
+            let mut pulses: WheelPulses = [None; 4];
+            for ((pulses, speed), remainder) in
+                pulses.iter_mut().zip(self.speeds).zip(&mut self.remainder)
+            {
+                if let Some(speed) = speed {
+                    let counted = *remainder + speed * dt * self.pulses_per_meter;
+                    let whole = counted.trunc();
+                    *remainder = counted - whole;
+                    *pulses = Some(whole as i32);
+                }
+            }
+            pulses
+        }
+    }
+
+    impl Encoder for SimulatedEncoder {
+        type Error = Infallible;
+
+        fn take_pulses(&mut self) -> Result<WheelPulses, Infallible> {
+            Ok(self.take_pulses_at(Instant::now()))
+        }
+
+        fn fitted(&self) -> [bool; 4] {
+            self.speeds.map(|speed| speed.is_some())
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//Emergency stop that can be set from any thread without taking the controller lock.
+//The flag is polled by the control loop and by running motions, the OE pin cuts the PCA9634 outputs in hardware.
+use log::debug;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+///GPIO wired to OE (active low) on the PCA9634, -1 until `init` has been called
+static OE_PIN: AtomicI32 = AtomicI32::new(-1);
+
+///Registers the output enable pin. The pin must already be configured as an output.
+pub fn init(oe_pin: i32) {
+    OE_PIN.store(oe_pin, Ordering::SeqCst);
+}
+
+///Latches the emergency stop and pulls OE high so the motor outputs go off at once
+pub fn trigger() {
+    ACTIVE.store(true, Ordering::SeqCst);
+    set_output_enable(false);
+    debug!("Nödstopp aktiverat!");
+}
+
+///Releases the emergency stop and enables the outputs again
+pub fn release() {
+    ACTIVE.store(false, Ordering::SeqCst);
+    set_output_enable(true);
+    debug!("Nödstopp släppt");
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+fn set_output_enable(enabled: bool) {
+    let pin = OE_PIN.load(Ordering::SeqCst);
+    if pin >= 0 {
+        //OE is active low
+        let level = if enabled { 0 } else { 1 };
+        unsafe {
+            esp_idf_sys::gpio_set_level(pin, level);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use json::JsonValue;
+use log::debug;
+use thingbuf::mpsc::blocking::{StaticChannel, StaticSender};
+
+///Message waiting to be published by the MQTT client
+#[derive(Debug, Default, Clone)]
+pub struct Outgoing {
+    pub topic: String,
+    pub payload: String,
+}
+
+///Queue from every task to the thread that owns the MQTT client. Nothing but that thread touches the client,
+///so events can be sent from the MQTT callback and the control loop without taking its lock.
+pub static OUTBOX: StaticChannel<Outgoing, 32> = StaticChannel::new();
+
+///Publishes messages about this vehicle through the outbox
+#[derive(Clone)]
+pub struct Publisher {
+    tx: StaticSender<Outgoing>,
+    carid: String,
+}
+
+impl Publisher {
+    pub fn new(tx: StaticSender<Outgoing>, carid: &str) -> Self {
+        Self {
+            tx,
+            carid: carid.to_owned(),
+        }
+    }
+
+    ///Topic for everything this vehicle reports
+    pub fn topic(&self, name: &str) -> String {
+        format!("/vehicle/{}/{}", self.carid, name)
+    }
+
+    ///Publishes `{"carID": .., "event": kind, ..fields}` on /vehicle/<carID>/events
+    pub fn event(&self, kind: &str, fields: JsonValue) {
+        self.event_on("events", kind, fields);
+    }
+
+    ///Like `event` but on /vehicle/<carID>/<name>, for event streams with their own topic
+    pub fn event_on(&self, name: &str, kind: &str, mut fields: JsonValue) {
+        if !fields.is_object() {
+            fields = JsonValue::new_object();
+        }
+        fields["carID"] = self.carid.as_str().into();
+        fields["event"] = kind.into();
+        self.send(self.topic(name), fields.dump());
+    }
+
+    ///Publishes `{"carID": .., ..fields}` on /vehicle/<carID>/telemetry
+    pub fn telemetry(&self, mut fields: JsonValue) {
+        if !fields.is_object() {
+            fields = JsonValue::new_object();
+        }
+        fields["carID"] = self.carid.as_str().into();
+        self.send(self.topic("telemetry"), fields.dump());
+    }
+
+    ///Queues a message. Drops it if the outbox is full rather than blocking the caller.
+    pub fn send(&self, topic: String, payload: String) {
+        if self.tx.try_send(Outgoing { topic, payload }).is_err() {
+            debug!("Utkön är full, meddelande slängt");
+        }
+    }
+}
@@ -0,0 +1,535 @@
+//Motion executor for block builder programs. Runs on its own thread so the MQTT callback never waits for a
+//program, and only takes the controller lock for the moment it takes to start or stop a motion.
+use crate::estop;
+use crate::events::Publisher;
+use crate::motordriver::MotorDriver;
+use crate::program::{self, Estimate, Instruction, Program, DEFAULT_SPEED};
+use crate::vehicle::{self, Vehicle};
+use json::{object, JsonValue};
+use log::{debug, error};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+///Programs that may wait behind the running one
+const QUEUE_LEN: usize = 4;
+///Set in `Control::led` when a program has chosen a colour
+const LED_SET: u32 = 1 << 24;
+
+///Handle for queueing and controlling programs. Cheap to clone.
+#[derive(Clone)]
+pub struct Executor {
+    tx: SyncSender<Job>,
+    control: Arc<Control>,
+    publisher: Publisher,
+}
+
+///State shared between the handles and the executor thread
+struct Control {
+    //Every cancel and preempting program bumps this, programs from an older generation stop or are skipped
+    generation: AtomicU32,
+    paused: AtomicBool,
+    //Instructions that may run while paused, added by `step`
+    steps: AtomicU32,
+    //Block being run, as reported in the events, e.g. "2.1.0". None when idle.
+    current: Mutex<Option<String>>,
+    //Colour from the running program as 0xRRGGBB with `LED_SET`, 0 when the program has not set one
+    led: AtomicU32,
+    //Held while a program is queued and reported, so it is accepted before it is started
+    submitting: Mutex<()>,
+}
+
+impl Control {
+    fn set_led(&self, colour: Option<[u8; 3]>) {
+        let value = match colour {
+            Some([red, green, blue]) => {
+                LED_SET | (red as u32) << 16 | (green as u32) << 8 | blue as u32
+            }
+            None => 0,
+        };
+        self.led.store(value, Ordering::SeqCst);
+    }
+}
+
+///A program waiting in the queue or running
+struct Job {
+    generation: u32,
+    program: Program,
+    //Id from the request, sent back in every progress event
+    correlation: Option<String>,
+}
+
+///Whether the program goes on after a block
+enum Flow {
+    Continue,
+    Stop,
+}
+
+///Why a program did not run to the end
+#[derive(Debug, Clone, PartialEq)]
+enum Abort {
+    EmergencyStop,
+    Cancelled,
+    Failed(String),
+}
+
+struct Worker<M> {
+    styrsystem: Arc<Mutex<Vehicle<M>>>,
+    publisher: Publisher,
+    control: Arc<Control>,
+}
+
+impl Executor {
+    ///Starts the executor thread for `styrsystem`
+    pub fn start<M>(styrsystem: Arc<Mutex<Vehicle<M>>>, publisher: Publisher) -> Self
+    where
+        M: MotorDriver + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        let control = Arc::new(Control {
+            generation: AtomicU32::new(0),
+            paused: AtomicBool::new(false),
+            steps: AtomicU32::new(0),
+            current: Mutex::new(None),
+            led: AtomicU32::new(0),
+            submitting: Mutex::new(()),
+        });
+        let worker = Worker {
+            styrsystem,
+            publisher: publisher.clone(),
+            control: Arc::clone(&control),
+        };
+        thread::spawn(move || worker.run(rx));
+        Self {
+            tx,
+            control,
+            publisher,
+        }
+    }
+
+    ///Queues a program and reports it as accepted with its estimate. With `preempt` the running program and
+    ///everything queued is dropped first, otherwise the program waits its turn. `correlation` is echoed in
+    ///the progress events. Fails if the queue is full.
+    pub fn submit(
+        &self,
+        program: Program,
+        estimate: Estimate,
+        correlation: Option<String>,
+        preempt: bool,
+    ) -> Result<(), &'static str> {
+        let _submitting = self.control.submitting.lock().unwrap();
+        let generation = if preempt {
+            self.control.generation.fetch_add(1, Ordering::SeqCst) + 1
+        } else {
+            self.control.generation.load(Ordering::SeqCst)
+        };
+        let job = Job {
+            generation,
+            program,
+            correlation: correlation.clone(),
+        };
+        match self.tx.try_send(job) {
+            Ok(_) => {
+                report(
+                    &self.publisher,
+                    correlation.as_deref(),
+                    "programAccepted",
+                    estimate.to_json(),
+                );
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => Err("Programkön är full"),
+            Err(TrySendError::Disconnected(_)) => Err("Programkörningen har stannat"),
+        }
+    }
+
+    ///Checks the program in `data` and estimates it with the calibration and max speed of `styrsystem`.
+    ///Queues it like `submit`, or for a dry run only reports the estimate. A rejection is reported too.
+    pub fn submit_json<M: MotorDriver>(
+        &self,
+        styrsystem: &Mutex<Vehicle<M>>,
+        data: &JsonValue,
+        correlation: Option<&str>,
+        dry_run: bool,
+        preempt: bool,
+    ) {
+        let planned = Program::from_json(data).and_then(|program| {
+            let styrsystem = styrsystem.lock().unwrap();
+            let estimate = program.estimate(styrsystem.get_calibration(), |speed| {
+                styrsystem.capped_speed(speed)
+            })?;
+            Ok((program, estimate))
+        });
+        match planned {
+            Ok((_, estimate)) if dry_run => {
+                report(
+                    &self.publisher,
+                    correlation,
+                    "programEstimate",
+                    estimate.to_json(),
+                );
+            }
+            Ok((program, estimate)) => {
+                let owned = correlation.map(str::to_owned);
+                if let Err(e) = self.submit(program, estimate, owned, preempt) {
+                    error!("{}", e);
+                    report(
+                        &self.publisher,
+                        correlation,
+                        "programRejected",
+                        object! { "error" => e },
+                    );
+                }
+            }
+            Err(e) => {
+                debug!("Program avvisat: {}", e);
+                report(&self.publisher, correlation, "programRejected", e.to_json());
+            }
+        }
+    }
+
+    ///Stops the running program and drops the queued ones. The vehicle keeps whatever command comes next,
+    ///so live control calls this before it takes over.
+    pub fn cancel(&self) {
+        self.control.generation.fetch_add(1, Ordering::SeqCst);
+        self.control.paused.store(false, Ordering::SeqCst);
+        self.control.steps.store(0, Ordering::SeqCst);
+    }
+
+    ///Halts the program. A motion that is under way ramps down and continues from where it was on resume.
+    ///Pausing while idle holds the next program before its first instruction.
+    pub fn pause(&self) {
+        self.control.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.control.steps.store(0, Ordering::SeqCst);
+        self.control.paused.store(false, Ordering::SeqCst);
+    }
+
+    ///Runs one instruction (or the rest of the interrupted one) and pauses again
+    pub fn step(&self) {
+        self.control.paused.store(true, Ordering::SeqCst);
+        self.control.steps.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.paused.load(Ordering::SeqCst)
+    }
+
+    ///LED colour set by the running program
+    pub fn led(&self) -> Option<[u8; 3]> {
+        let value = self.control.led.load(Ordering::SeqCst);
+        if value & LED_SET == 0 {
+            return None;
+        }
+        Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+    }
+
+    ///Path of the block being run, as in the progress events. None when no program is running.
+    pub fn current_instruction(&self) -> Option<String> {
+        self.control.current.lock().unwrap().clone()
+    }
+}
+
+impl<M: MotorDriver> Worker<M> {
+    fn run(self, rx: Receiver<Job>) {
+        while let Ok(job) = rx.recv() {
+            //Let `submit` finish reporting the program as accepted
+            drop(self.control.submitting.lock().unwrap());
+            if job.generation != self.control.generation.load(Ordering::SeqCst) {
+                debug!("Program ersatt innan start");
+                continue;
+            }
+            self.report(
+                &job,
+                "programStarted",
+                object! { "instructions" => job.program.instructions.len() },
+            );
+            let result = self.run_program(&job);
+            let index = self.control.current.lock().unwrap().take();
+            self.control.set_led(None);
+            match result {
+                Ok(_) => self.report(&job, "programCompleted", object! {}),
+                Err(Abort::Failed(e)) => {
+                    error!("Program avbrutet: {}", e);
+                    self.stop(&job);
+                    self.report(
+                        &job,
+                        "programFailed",
+                        object! { "index" => index, "error" => e },
+                    );
+                }
+                Err(Abort::EmergencyStop) => {
+                    debug!("Program avbrutet av nödstopp!");
+                    self.report(
+                        &job,
+                        "programAborted",
+                        object! { "index" => index, "reason" => "emergencyStop" },
+                    );
+                }
+                Err(Abort::Cancelled) => {
+                    debug!("Program avbrutet");
+                    self.report(
+                        &job,
+                        "programAborted",
+                        object! { "index" => index, "reason" => "cancelled" },
+                    );
+                }
+            }
+        }
+    }
+
+    fn report(&self, job: &Job, kind: &str, fields: JsonValue) {
+        report(&self.publisher, job.correlation.as_deref(), kind, fields);
+    }
+
+    fn run_program(&self, job: &Job) -> Result<(), Abort> {
+        let mut speed = DEFAULT_SPEED;
+        for (index, instruction) in job.program.instructions.iter().enumerate() {
+            if let Flow::Stop = self.run_instruction(job, &[index], instruction, &mut speed)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    ///Runs one block, and everything inside it for a repeat. `path` is the index of the block with one entry
+    ///per repeat it sits in, reported as e.g. "3.1".
+    fn run_instruction(
+        &self,
+        job: &Job,
+        path: &[usize],
+        instruction: &Instruction,
+        speed: &mut f32,
+    ) -> Result<Flow, Abort> {
+        let index = program::index_str(path);
+        debug!("Instruktion {}", index);
+        *self.control.current.lock().unwrap() = Some(index.clone());
+        self.report(
+            job,
+            "instructionStarted",
+            object! { "index" => index.as_str() },
+        );
+        let flow = self.run_block(job, path, instruction, speed)?;
+        self.report(
+            job,
+            "instructionFinished",
+            object! { "index" => index.as_str() },
+        );
+        Ok(flow)
+    }
+
+    fn run_block(
+        &self,
+        job: &Job,
+        path: &[usize],
+        instruction: &Instruction,
+        speed: &mut f32,
+    ) -> Result<Flow, Abort> {
+        let speed = match *instruction {
+            Instruction::Repeat {
+                times,
+                ref instructions,
+            } => {
+                for _ in 0..times {
+                    for (i, inner) in instructions.iter().enumerate() {
+                        let inner_path = [path, &[i]].concat();
+                        if let Flow::Stop = self.run_instruction(job, &inner_path, inner, speed)? {
+                            return Ok(Flow::Stop);
+                        }
+                    }
+                }
+                return Ok(Flow::Continue);
+            }
+            Instruction::SetSpeed(new_speed) => {
+                *speed = new_speed;
+                return Ok(Flow::Continue);
+            }
+            Instruction::Led(colour) => {
+                self.control.set_led(Some(colour));
+                return Ok(Flow::Continue);
+            }
+            Instruction::Stop => {
+                self.with_vehicle(job, |styrsystem| styrsystem.stop_vehicle())?;
+                self.wait_settled(job)?;
+                return Ok(Flow::Stop);
+            }
+            _ => instruction.speed().unwrap_or(*speed),
+        };
+        self.run_timed(job, path, |styrsystem, from, left| match *instruction {
+            Instruction::Forward { meters, .. } => {
+                styrsystem.start_forward(left.unwrap_or(meters), speed, from)
+            }
+            Instruction::Backward { meters, .. } => {
+                styrsystem.start_backward(left.unwrap_or(meters), speed, from)
+            }
+            Instruction::RotateLeft { degrees, .. } => {
+                styrsystem.start_rotate_l(degrees, speed, from)
+            }
+            Instruction::RotateRight { degrees, .. } => {
+                styrsystem.start_rotate_r(degrees, speed, from)
+            }
+            Instruction::Arc {
+                radius, degrees, ..
+            } => styrsystem.start_arc(radius, degrees, speed),
+            Instruction::Wait(duration) => Ok(duration),
+            _ => Ok(Duration::ZERO),
+        })?;
+        Ok(Flow::Continue)
+    }
+
+    ///Runs a motion started by `start` for the time it returns, or until the vehicle reports it done, then
+    ///ramps the vehicle down. A pause stops the motion and it is started again for the time that was left
+    ///when the program continues. `start` gets the heading from when the motion first started, and the
+    ///distance left when a move closed on the encoders is started again.
+    fn run_timed(
+        &self,
+        job: &Job,
+        path: &[usize],
+        start: impl Fn(&mut Vehicle<M>, Option<f32>, Option<f32>) -> Result<Duration, M::Error>,
+    ) -> Result<(), Abort> {
+        let mut stepping = self.hold(job, path)?;
+        let from = self.with_vehicle(job, |styrsystem| Ok(styrsystem.get_heading()))?;
+        //Time and distance left of an instruction that was interrupted by pause
+        let mut remaining: Option<Duration> = None;
+        let mut distance: Option<f32> = None;
+        loop {
+            let duration =
+                self.with_vehicle(job, |styrsystem| start(styrsystem, from, distance))?;
+            match self.wait(job, remaining.unwrap_or(duration), stepping)? {
+                None => break,
+                Some(left) => {
+                    distance = self.with_vehicle(job, |styrsystem| {
+                        let distance = styrsystem.get_distance_left();
+                        styrsystem.stop_vehicle()?;
+                        Ok(distance)
+                    })?;
+                    self.wait_settled(job)?;
+                    stepping = self.hold(job, path)?;
+                    remaining = Some(left);
+                }
+            }
+        }
+        self.with_vehicle(job, |styrsystem| styrsystem.stop_vehicle())?;
+        self.wait_settled(job)
+    }
+
+    ///Runs `f` on the vehicle unless the program has been stopped. The check is made under the lock so a
+    ///command that cancelled the program is never overwritten by it.
+    fn with_vehicle<T>(
+        &self,
+        job: &Job,
+        f: impl FnOnce(&mut Vehicle<M>) -> Result<T, M::Error>,
+    ) -> Result<T, Abort> {
+        let mut styrsystem = self.styrsystem.lock().unwrap();
+        self.check(job)?;
+        f(&mut styrsystem).map_err(|e| Abort::Failed(e.to_string()))
+    }
+
+    fn check(&self, job: &Job) -> Result<(), Abort> {
+        if estop::is_active() {
+            Err(Abort::EmergencyStop)
+        } else if job.generation != self.control.generation.load(Ordering::SeqCst) {
+            Err(Abort::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    ///Blocks while the program is paused. Returns true if a single step released it.
+    fn hold(&self, job: &Job, path: &[usize]) -> Result<bool, Abort> {
+        let index = program::index_str(path);
+        let mut reported = false;
+        loop {
+            self.check(job)?;
+            if !self.control.paused.load(Ordering::SeqCst) {
+                if reported {
+                    self.report(job, "programResumed", object! { "index" => index });
+                }
+                return Ok(false);
+            }
+            if self.take_step() {
+                self.report(job, "programStep", object! { "index" => index });
+                return Ok(true);
+            }
+            if !reported {
+                debug!("Program pausat vid instruktion {}", index);
+                self.report(job, "programPaused", object! { "index" => index.as_str() });
+                reported = true;
+            }
+            sleep(vehicle::TICK);
+        }
+    }
+
+    ///Uses up one of the steps given by `step`
+    fn take_step(&self) -> bool {
+        self.control
+            .steps
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |steps| {
+                steps.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    ///Lets a motion run for `duration`, or until the vehicle reports it done, without holding the controller.
+    ///Returns the time left if the program was paused before it was done. A stepped instruction always runs to the end, and so does one that is
+    ///under way when `step` is given.
+    fn wait(
+        &self,
+        job: &Job,
+        duration: Duration,
+        mut stepping: bool,
+    ) -> Result<Option<Duration>, Abort> {
+        let deadline = Instant::now() + duration;
+        loop {
+            self.check(job)?;
+            let now = Instant::now();
+            if now >= deadline || self.styrsystem.lock().unwrap().is_move_done() {
+                return Ok(None);
+            }
+            if !stepping && self.control.paused.load(Ordering::SeqCst) {
+                if self.take_step() {
+                    stepping = true;
+                } else {
+                    return Ok(Some(deadline - now));
+                }
+            }
+            sleep((deadline - now).min(vehicle::TICK));
+        }
+    }
+
+    ///Waits until the control loop has ramped the wheels down
+    fn wait_settled(&self, job: &Job) -> Result<(), Abort> {
+        loop {
+            self.check(job)?;
+            if self.styrsystem.lock().unwrap().is_settled() {
+                return Ok(());
+            }
+            sleep(vehicle::TICK);
+        }
+    }
+
+    ///Stops the vehicle after a failed step, unless someone else has taken over
+    fn stop(&self, job: &Job) {
+        if let Err(Abort::Failed(e)) =
+            self.with_vehicle(job, |styrsystem| styrsystem.stop_vehicle())
+        {
+            error!("{}", e);
+        }
+    }
+}
+
+///Publishes a progress event on /vehicle/<carID>/program, tagged with the correlation id of the request
+pub fn report(publisher: &Publisher, correlation: Option<&str>, kind: &str, mut fields: JsonValue) {
+    if let Some(correlation) = correlation {
+        fields["correlationId"] = correlation.into();
+    }
+    publisher.event_on("program", kind, fields);
+}
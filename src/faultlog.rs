@@ -0,0 +1,68 @@
+use log::{debug, error};
+use std::fmt;
+
+///Logs a reading that keeps failing once, and once more when it works again. The control loop reads every
+///sensor each tick, an error logged every time would flood the log.
+pub struct FaultLog {
+    //Vad som läses, inleder loggmeddelandet
+    name: &'static str,
+    //Senaste felet, None när läsningen fungerar
+    error: Option<String>,
+}
+
+impl FaultLog {
+    pub fn new(name: &'static str) -> Self {
+        Self { name, error: None }
+    }
+
+    ///Passes on a successful reading. A failure gives None and is logged when it differs from the last one.
+    pub fn check<T, E: fmt::Display>(&mut self, reading: Result<T, E>) -> Option<T> {
+        match reading {
+            Ok(value) => {
+                if self.update(None) {
+                    debug!("{} fungerar igen", self.name);
+                }
+                Some(value)
+            }
+            Err(e) => {
+                if self.update(Some(e.to_string())) {
+                    error!("{}: {}", self.name, e);
+                }
+                None
+            }
+        }
+    }
+
+    ///Stores the latest error, true when it differs from the one before
+    fn update(&mut self, error: Option<String>) -> bool {
+        let changed = error != self.error;
+        self.error = error;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_only_when_the_state_changes() {
+        let mut log = FaultLog::new("Test");
+        assert!(!log.update(None));
+        assert!(log.update(Some("timeout".to_owned())));
+        assert!(!log.update(Some("timeout".to_owned())));
+        assert!(log.update(Some("nack".to_owned())));
+        assert!(log.update(None));
+        assert!(!log.update(None));
+    }
+
+    #[test]
+    fn passes_on_readings() {
+        let mut log = FaultLog::new("Test");
+        assert_eq!(log.check(Ok::<_, &str>(3)), Some(3));
+        assert_eq!(log.check(Err::<i32, _>("timeout")), None);
+        assert_eq!(log.error.as_deref(), Some("timeout"));
+        assert_eq!(log.check(Ok::<_, &str>(4)), Some(4));
+        assert_eq!(log.error, None);
+    }
+}
@@ -0,0 +1,56 @@
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+use std::sync::{Arc, Mutex};
+
+///Handle to an I2C bus shared by several drivers (PCA9634, IMU...). Every transfer locks the bus, so a
+///transfer is never split by another driver. The `shared-bus` crate only implements the embedded-hal 0.2
+///traits, this implements the 1.0 `I2c` trait the drivers are written against.
+pub struct SharedI2c<I2C> {
+    bus: Arc<Mutex<I2C>>,
+}
+
+impl<I2C> SharedI2c<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            bus: Arc::new(Mutex::new(i2c)),
+        }
+    }
+}
+
+impl<I2C> Clone for SharedI2c<I2C> {
+    fn clone(&self) -> Self {
+        Self {
+            bus: Arc::clone(&self.bus),
+        }
+    }
+}
+
+impl<I2C: I2c> ErrorType for SharedI2c<I2C> {
+    type Error = I2C::Error;
+}
+
+impl<I2C: I2c> I2c for SharedI2c<I2C> {
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.bus.lock().unwrap().read(address, read)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.bus.lock().unwrap().write(address, write)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.bus.lock().unwrap().write_read(address, write, read)
+    }
+
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.bus.lock().unwrap().transaction(address, operations)
+    }
+}
@@ -0,0 +1,138 @@
+use crate::config::Config;
+use json::{object, JsonValue};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+///Weight of each still sample when learning the gyro offset
+const BIAS_FILTER: f32 = 0.02;
+///Samples further apart than this are not integrated, the rotation in between is unknown
+const MAX_GAP: Duration = Duration::from_millis(500);
+
+///One reading from an IMU
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ImuSample {
+    ///Acceleration (g) along x, y and z
+    pub accel: [f32; 3],
+    ///Rotation rate (degrees/s) around x, y and z. Positive z turns the vehicle left.
+    pub gyro: [f32; 3],
+}
+
+///Interface for IMUs (MPU-6050...). The control loop reads it every tick and hands the samples to the
+///vehicle controller.
+pub trait Imu {
+    type Error: fmt::Display;
+
+    fn read(&mut self) -> Result<ImuSample, Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadingConfig {
+    ///Closes rotations and straight moves on the heading when an IMU is fitted
+    pub enabled: bool,
+    ///The IMU is mounted upside down
+    pub inverted: bool,
+    ///Steering (percent) per degree of heading error when driving straight
+    pub kp: f32,
+    ///A rotation is done within this many degrees of its goal
+    pub tolerance: f32,
+}
+
+impl Default for HeadingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            inverted: false,
+            kp: 2.0,
+            tolerance: 2.0,
+        }
+    }
+}
+
+impl HeadingConfig {
+    pub fn is_valid(&self) -> bool {
+        self.kp >= 0.0 && self.tolerance > 0.0
+    }
+}
+
+impl Config for HeadingConfig {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "enabled" => self.enabled,
+            "inverted" => self.inverted,
+            "kp" => self.kp,
+            "tolerance" => self.tolerance,
+        }
+    }
+
+    ///Parses `{"enabled": true, "inverted": false, "kp": 2.0, "tolerance": 2.0}`
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            enabled: data["enabled"].as_bool()?,
+            inverted: data["inverted"].as_bool().unwrap_or(false),
+            kp: data["kp"].as_f32()?,
+            tolerance: data["tolerance"].as_f32()?,
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
+
+///Yaw integrated from the gyro. The gyro offset is learned whenever the vehicle is known to stand still.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HeadingEstimator {
+    yaw: Option<f32>,
+    rate: f32,
+    bias: f32,
+    last: Option<Instant>,
+}
+
+impl HeadingEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Adds a sample. While `still` the wheels are stopped, so the rate is taken as the gyro offset.
+    pub fn update(&mut self, sample: &ImuSample, still: bool, inverted: bool) {
+        let now = Instant::now();
+        let dt = self.last.map(|last| now.duration_since(last));
+        self.last = Some(now);
+        let rate = if inverted {
+            -sample.gyro[2]
+        } else {
+            sample.gyro[2]
+        };
+        let yaw = self.yaw.get_or_insert(0.0);
+        if still {
+            self.bias += (rate - self.bias) * BIAS_FILTER;
+            self.rate = 0.0;
+            return;
+        }
+        self.rate = rate - self.bias;
+        if let Some(dt) = dt.filter(|dt| *dt <= MAX_GAP) {
+            *yaw += self.rate * dt.as_secs_f32();
+        }
+    }
+
+    ///Degrees turned left since start, not wrapped. None until the first sample.
+    pub fn yaw(&self) -> Option<f32> {
+        self.yaw
+    }
+
+    ///Rotation rate (degrees/s) after the offset, positive to the left
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+}
+
+///Heading wrapped to -180..180 degrees
+pub fn wrap_degrees(degrees: f32) -> f32 {
+    let wrapped = degrees.rem_euclid(360.0);
+    if wrapped > 180.0 {
+        wrapped - 360.0
+    } else {
+        wrapped
+    }
+}
@@ -0,0 +1,106 @@
+use crate::overload::{PowerMonitor, PowerSample};
+use embedded_hal::i2c::{Error, ErrorKind, I2c};
+use std::fmt;
+
+///Address with A0 and A1 low
+pub const DEFAULT_ADDRESS: u8 = 0x40;
+///Shunt on the common breakout boards (R100)
+pub const DEFAULT_SHUNT: f32 = 0.1;
+
+const CONFIGURATION: u8 = 0x00;
+const SHUNT_VOLTAGE: u8 = 0x01;
+const BUS_VOLTAGE: u8 = 0x02;
+
+///32 V bus range, ±320 mV shunt range, 12 bit bus samples, shunt averaged over 8 samples (4.3 ms),
+///shunt and bus measured continuously
+const CONFIG: u16 = 0x39DF;
+const RESET: u16 = 0x8000;
+///Volts per bit in the shunt voltage register
+const SHUNT_LSB: f32 = 10e-6;
+///Volts per bit in the bus voltage register, after the three status bits
+const BUS_LSB: f32 = 4e-3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerError {
+    Write(ErrorKind),
+    Read(ErrorKind),
+    ///The chip did not keep the configuration, something else answered on the address
+    WrongDevice(u16),
+}
+
+impl fmt::Display for PowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerError::Write(kind) => {
+                write!(f, "I2C-fel vid skrivning till strömmätare: {:?}", kind)
+            }
+            PowerError::Read(kind) => write!(f, "I2C-fel vid läsning av strömmätare: {:?}", kind),
+            PowerError::WrongDevice(config) => {
+                write!(f, "Okänd strömmätare, konfiguration {:04x}", config)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PowerError {}
+
+///TI INA219 current and power monitor on the motor supply. The current is worked out from the shunt voltage,
+///so the calibration register is not used.
+pub struct Ina219<I2C> {
+    i2c: I2C,
+    address: u8,
+    //Shuntmotstånd i ohm
+    shunt: f32,
+}
+
+impl<I2C: I2c> Ina219<I2C> {
+    pub fn new(i2c: I2C, address: u8, shunt: f32) -> Self {
+        Self {
+            i2c,
+            address,
+            shunt,
+        }
+    }
+
+    ///Resets the chip and starts continuous measurements
+    pub fn init(&mut self) -> Result<(), PowerError> {
+        self.write_register(CONFIGURATION, RESET)?;
+        self.write_register(CONFIGURATION, CONFIG)?;
+        //The INA219 has no id register, check that the configuration stuck instead
+        let config = self.read_register(CONFIGURATION)?;
+        if config != CONFIG {
+            return Err(PowerError::WrongDevice(config));
+        }
+        Ok(())
+    }
+
+    fn write_register(&mut self, register: u8, value: u16) -> Result<(), PowerError> {
+        let [high, low] = value.to_be_bytes();
+        self.i2c
+            .write(self.address, &[register, high, low])
+            .map_err(|e| PowerError::Write(e.kind()))
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u16, PowerError> {
+        let mut data = [0u8; 2];
+        self.i2c
+            .write_read(self.address, &[register], &mut data)
+            .map_err(|e| PowerError::Read(e.kind()))?;
+        Ok(u16::from_be_bytes(data))
+    }
+}
+
+impl<I2C: I2c> PowerMonitor for Ina219<I2C> {
+    type Error = PowerError;
+
+    fn read(&mut self) -> Result<PowerSample, PowerError> {
+        let shunt = self.read_register(SHUNT_VOLTAGE)? as i16 as f32 * SHUNT_LSB;
+        let bus = (self.read_register(BUS_VOLTAGE)? >> 3) as f32 * BUS_LSB;
+        let current = shunt / self.shunt;
+        Ok(PowerSample {
+            voltage: bus,
+            current,
+            power: bus * current,
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use crate::config::Config;
+use json::{object, JsonValue};
+
+///Shaping of analog stick input before it is turned into a twist command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JoystickConfig {
+    ///Stick deflection (0-1) that is treated as centered
+    pub deadzone: f32,
+    ///Blend between linear (0) and cubic (1) response, softens the stick around center
+    pub expo: f32,
+}
+
+impl Default for JoystickConfig {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.05,
+            expo: 0.3,
+        }
+    }
+}
+
+impl JoystickConfig {
+    pub fn is_valid(&self) -> bool {
+        (0.0..1.0).contains(&self.deadzone) && (0.0..=1.0).contains(&self.expo)
+    }
+
+    ///Shapes one axis (-1..1): removes the deadzone, rescales the rest to the full range and applies expo
+    pub fn shape(&self, value: f32) -> f32 {
+        let magnitude = value.abs().min(1.0);
+        if magnitude <= self.deadzone {
+            return 0.0;
+        }
+        let scaled = (magnitude - self.deadzone) / (1.0 - self.deadzone);
+        let curved = (1.0 - self.expo) * scaled + self.expo * scaled.powi(3);
+        value.signum() * curved
+    }
+
+    ///Maps stick input to linear and angular speed in percent. Stick right (+x) turns right, stick up (+y)
+    ///drives forward and `throttle` (0-1) scales both.
+    pub fn to_twist(self, x: f32, y: f32, throttle: f32) -> (f32, f32) {
+        let linear = self.shape(y) * throttle * 100.0;
+        let angular = -self.shape(x) * throttle * 100.0;
+        (linear, angular)
+    }
+}
+
+impl Config for JoystickConfig {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "deadzone" => self.deadzone,
+            "expo" => self.expo,
+        }
+    }
+
+    ///Parses `{"deadzone": 0.05, "expo": 0.3}`
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            deadzone: data["deadzone"].as_f32()?,
+            expo: data["expo"].as_f32()?,
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
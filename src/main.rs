@@ -3,11 +3,32 @@
 #![allow(unused_imports)]
 
 use crate::controllerhal::PCA9634;
+use crate::encoder::Encoder;
+use crate::events::Publisher;
+use crate::executor::Executor;
+use crate::faultlog::FaultLog;
+use crate::i2cbus::SharedI2c;
+use crate::imu::Imu;
+use crate::ina219::Ina219;
 use crate::leddriver::WS2812RMT;
+use crate::mpu6050::Mpu6050;
+use crate::motordriver::Wheel;
+use crate::overload::PowerMonitor;
+use crate::programstore::ProgramStore;
+use crate::safety::RangeSensor;
+use crate::settings::Settings;
+use crate::vehicle::Vehicle;
+use crate::vl53l0x::Vl53l0x;
 use anyhow::Result;
 use embedded_hal::digital::OutputPin;
 use embedded_svc::mqtt::client;
-use esp_idf_hal::{gpio::PinDriver, i2c, peripherals::Peripherals, units::*};
+use esp_idf_hal::{
+    adc::{self, attenuation, AdcChannelDriver, AdcDriver},
+    gpio::PinDriver,
+    i2c,
+    peripherals::Peripherals,
+    units::*,
+};
 use esp_idf_svc::{
     //wifi::EspWifi,
     eventloop::EspSystemEventLoop,
@@ -16,8 +37,7 @@ use esp_idf_svc::{
     nvs::EspDefaultNvsPartition,
 };
 use esp_idf_sys as _;
-use log::debug;
-use shared_bus;
+use log::{debug, error};
 use std::{
     env,
     sync::{Arc, Mutex},
@@ -25,12 +45,43 @@ use std::{
     time::Duration, //for threads!
 };
 //use controllerhal::{DeviceAddr, PCA9634};
+mod battery;
+mod calibration;
+mod calibrator;
+mod collision;
+mod config;
 mod controllerhal;
+mod dutycurve;
+mod encoder;
+mod estop;
+mod events;
+mod executor;
+mod faultlog;
+mod i2cbus;
+mod imu;
+mod ina219;
+mod joystick;
 mod leddriver;
+mod motordriver;
+mod mpu6050;
 mod mqtt;
+mod overload;
+mod pid;
+mod pose;
+mod program;
+mod programstore;
+mod ramp;
+mod safety;
+mod settings;
+mod speedcontrol;
+mod vehicle;
+mod vl53l0x;
 mod wifi;
 //mod ctrl;
 
+///Vehicle controller driving the SCB Motordrive3 board
+pub type Styrsystem = Vehicle<PCA9634<SharedI2c<I2cDriver<'static>>>>;
+
 fn main() {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -44,11 +95,20 @@ fn main() {
     const WIFI_PASSWORD: &str = env!("WIFI_PASSWORD");
     const MQTT_ADRESS: &str = env!("MQTT_ADRESS");
     const FORDON_ID: &str = env!("FORDON_ID");
+    //How often speeds and state are published on the telemetry topic
+    const TELEMETRY_PERIOD: Duration = Duration::from_millis(500);
+
+    //Settings stored on the vehicle (wheel map etc.)
+    let settings = Settings::new(nvs.clone()).unwrap();
+    //Named block builder programs
+    let store = Arc::new(Mutex::new(ProgramStore::new(nvs.clone()).unwrap()));
 
     //----------------------I2C och Styrsystem setup----------------------
     //let mut oe = PinDriver::output(peripherals.pins.gpio1).unwrap();
 
     let mut oe = PinDriver::output(peripherals.pins.gpio1).unwrap();
+    //OE doubles as the hardware cut for the emergency stop
+    estop::init(oe.pin());
     let led = peripherals.pins.gpio2;
     let channel = peripherals.rmt.channel0;
     let mut ws2812 = WS2812RMT::new(led, channel).unwrap();
@@ -62,20 +122,164 @@ fn main() {
     let config = I2cConfig::new().baudrate(100.kHz().into());
     let i2c: I2cDriver<'static> = I2cDriver::new(peripherals.i2c0, sda, scl, &config).unwrap();
 
-    //shared bus configuration
-    //let bus: &'static _ = shared_bus::new_std!(I2cDriver<'_> = i2c).unwrap();
+    //shared bus configuration, the motor driver and the sensors sit on the same bus
+    let bus = SharedI2c::new(i2c);
     debug!("-----STARTAR STYRSYSTEM-----");
-    let mut styrsystem: PCA9634<I2cDriver<'static>> =
-        controllerhal::PCA9634::new(i2c, controllerhal::DeviceAddr::DEFADR);
+    let mut motordrive: PCA9634<SharedI2c<I2cDriver<'static>>> = controllerhal::PCA9634::new(
+        bus.clone(),
+        controllerhal::DeviceAddr::DEFADR,
+        settings.wheel_map(),
+    );
     debug!("Initierar register...");
-    styrsystem.init_controller();
-    let _ = oe.set_low();
+    if let Err(e) = motordrive.init_controller() {
+        error!("Kunde ej initiera styrsystem: {}", e);
+    }
+    //Wheel encoders for the speed control. The ESP32-C3 has no pulse counter, there the wheels run open loop
+    //and the speed control stays off.
+    #[cfg(any(esp32, esp32s2, esp32s3))]
+    let mut encoder = {
+        let mut encoder = encoder::PcntEncoder::new();
+        //A and B of the front encoders, change to match the wiring of the chassis
+        encoder
+            .add_wheel(
+                Wheel::FrontLeft,
+                peripherals.pcnt0,
+                peripherals.pins.gpio4,
+                peripherals.pins.gpio5,
+            )
+            .unwrap();
+        encoder
+            .add_wheel(
+                Wheel::FrontRight,
+                peripherals.pcnt1,
+                peripherals.pins.gpio6,
+                peripherals.pins.gpio7,
+            )
+            .unwrap();
+        encoder
+    };
+    #[cfg(not(any(esp32, esp32s2, esp32s3)))]
+    let mut encoder = encoder::NoEncoder;
+
+    let mut styrsystem: Styrsystem = Vehicle::new(
+        motordrive,
+        settings.config(),
+        settings.config(),
+    );
+    styrsystem.set_joystick_config(settings.config());
+    styrsystem.set_deadman_timeout(settings.deadman_timeout());
+    styrsystem.set_calibration(settings.config());
+    styrsystem.set_encoders(encoder.fitted());
+    if let Err(e) = styrsystem.set_speed_control_config(settings.config()) {
+        error!("{}", e);
+    }
+    styrsystem.set_heading_config(settings.config());
+    styrsystem.set_safety_config(settings.config());
+    styrsystem.set_collision_config(settings.config());
+    styrsystem.set_overload_config(settings.config());
+    if let Err(e) = styrsystem.set_battery_config(settings.config()) {
+        error!("{}", e);
+    }
+    //Gyro for the heading, the vehicle runs on time alone without it
+    let mut imu = Mpu6050::new(bus.clone(), mpu6050::DEFAULT_ADDRESS);
+    let mut imu = match imu.init() {
+        Ok(_) => Some(imu),
+        Err(e) => {
+            error!("Ingen IMU: {}", e);
+            None
+        }
+    };
+    //Distance sensor ahead for the obstacle stop, without it forward speed is not limited
+    let mut ranger = Vl53l0x::new(bus.clone(), vl53l0x::DEFAULT_ADDRESS);
+    let mut ranger = match ranger.init() {
+        Ok(_) => Some(ranger),
+        Err(e) => {
+            error!("Ingen avståndssensor: {}", e);
+            None
+        }
+    };
+    //Current on the motor supply for the stall and overcurrent protection
+    let mut power = Ina219::new(bus.clone(), ina219::DEFAULT_ADDRESS, ina219::DEFAULT_SHUNT);
+    let mut power = match power.init() {
+        Ok(_) => Some(power),
+        Err(e) => {
+            error!("Ingen strömmätare: {}", e);
+            None
+        }
+    };
+    //An emergency stop sent while starting up keeps the outputs cut
+    if !estop::is_active() {
+        let _ = oe.set_low();
+    }
     debug!("Provkör!");
     //styrsystem.drive();
     debug!("----------------------------");
     let styrsystem = Arc::new(Mutex::new(styrsystem));
     //let styrsystem = Arc::new(styrsystem);
     //let styrsystem_clone = Arc::clone(&styrsystem)
+
+    //Everything the vehicle publishes goes through the outbox to the thread owning the MQTT client
+    let (outbox_tx, outbox_rx) = events::OUTBOX.split();
+    let publisher = Publisher::new(outbox_tx, FORDON_ID);
+
+    //Battery voltage through the divider on ADC1 channel 3, 11 dB attenuation reads up to about 2.5 V
+    let mut battery = match (
+        AdcDriver::new(peripherals.adc1, &adc::config::Config::new().calibration(true)),
+        AdcChannelDriver::<{ attenuation::DB_11 }, _>::new(peripherals.pins.gpio3),
+    ) {
+        (Ok(adc), Ok(pin)) => Some((adc, pin)),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Ingen batterimätning: {}", e);
+            None
+        }
+    };
+
+    //Control loop: measures the wheels and ramps them toward the commanded speeds at a fixed tick
+    let control_clone = Arc::clone(&styrsystem);
+    let control_publisher = publisher.clone();
+    //Sensors and the driver are polled every tick, failures are logged when they start and stop
+    let mut encoder_log = FaultLog::new("Pulsgivare");
+    let mut imu_log = FaultLog::new("IMU");
+    let mut range_log = FaultLog::new("Avståndssensor");
+    let mut power_log = FaultLog::new("Strömmätare");
+    let mut battery_log = FaultLog::new("Batterispänning");
+    let mut driver_log = FaultLog::new("Motordrivare");
+    thread::spawn(move || loop {
+        let pulses = encoder.take_pulses();
+        let sample = imu.as_mut().map(|imu| imu.read());
+        let range = ranger.as_mut().map(|ranger| ranger.read());
+        let current = power.as_mut().map(|power| power.read());
+        //Millivolts with calibration on
+        let voltage = battery.as_mut().map(|(adc, pin)| adc.read(pin));
+        let events = {
+            let mut styrsystem = control_clone.lock().unwrap();
+            if let Some(pulses) = encoder_log.check(pulses) {
+                styrsystem.update_encoders(pulses);
+            }
+            let mut driven = Ok(());
+            if let Some(sample) = imu_log.check(sample.transpose()).flatten() {
+                driven = styrsystem.update_imu(sample);
+            }
+            if let Some(range) = range_log.check(range.transpose()).flatten().flatten() {
+                styrsystem.update_range(range);
+            }
+            if let Some(sample) = power_log.check(current.transpose()).flatten() {
+                styrsystem.update_power(sample);
+            }
+            if let Some(millivolts) = battery_log.check(voltage.transpose()).flatten() {
+                driven = driven.and(styrsystem.update_battery(millivolts as f32 / 1000.0));
+            }
+            driver_log.check(driven.and(styrsystem.tick()));
+            styrsystem.take_events()
+        };
+        for event in events {
+            control_publisher.event(event.name(), event.to_json());
+        }
+        sleep(vehicle::TICK);
+    });
+
+    //Block builder programs run on their own thread so the MQTT callback is never held up
+    let executor = Executor::start(Arc::clone(&styrsystem), publisher.clone());
     //--------------------------------------------------------------------
 
     //-----------------------------WIFI-modul-----------------------------
@@ -86,9 +290,68 @@ fn main() {
     //----------------------------MQTT Klient-----------------------------
     //Creating Atomic Reference Counting for handling of controller instance in concurrency
     let styrsys_mqtt_clone = Arc::clone(&styrsystem);
-    let client = mqtt::mqtt_init(MQTT_ADRESS, styrsys_mqtt_clone, FORDON_ID);
+    let autorun = settings.autorun();
+    let settings = Arc::new(Mutex::new(settings));
+    let client = mqtt::mqtt_init(
+        MQTT_ADRESS,
+        styrsys_mqtt_clone,
+        Arc::clone(&settings),
+        Arc::clone(&store),
+        executor.clone(),
+        publisher.clone(),
+        FORDON_ID,
+    );
 
     let client = Arc::new(Mutex::new(client));
+
+    //Program chosen to run at startup. The delay gives time to put the vehicle down or hit the emergency stop.
+    if let Some((name, delay)) = autorun {
+        let autorun_executor = executor.clone();
+        let autorun_styrsystem = Arc::clone(&styrsystem);
+        thread::spawn(move || {
+            debug!("Kör {} om {:?}", name, delay);
+            sleep(delay);
+            if estop::is_active() {
+                debug!("Nödstopp aktivt, startprogrammet körs inte");
+                return;
+            }
+            let program = store.lock().unwrap().load(&name);
+            match program {
+                Ok(program) => autorun_executor.submit_json(
+                    &autorun_styrsystem,
+                    &program,
+                    Some("autorun"),
+                    false,
+                    false,
+                ),
+                Err(e) => error!("Kunde ej läsa startprogrammet {}: {}", name, e),
+            }
+        });
+    }
+
+    //Publishes everything queued in the outbox
+    let outbox_client = Arc::clone(&client);
+    thread::spawn(move || {
+        while let Some(msg) = outbox_rx.recv() {
+            let mut client = outbox_client.lock().unwrap();
+            if let Err(e) = client.publish(
+                &msg.topic,
+                client::QoS::AtLeastOnce,
+                false,
+                msg.payload.as_bytes(),
+            ) {
+                error!("Kunde ej publicera {}: {}", msg.topic, e);
+            }
+        }
+    });
+    //Speeds and state for the dashboards
+    let telemetry_styrsystem = Arc::clone(&styrsystem);
+    let telemetry_publisher = publisher.clone();
+    thread::spawn(move || loop {
+        let telemetry = telemetry_styrsystem.lock().unwrap().telemetry();
+        telemetry_publisher.telemetry(telemetry);
+        sleep(TELEMETRY_PERIOD);
+    });
     //--------------------------------------------------------------------
     //Subscribe to topic in a temporary scope. Creaates a clone reference that dies at the end of scope.
     {
@@ -98,40 +361,30 @@ fn main() {
     }
 
     let client_clone = Arc::clone(&client);
-    let styrsys_clone = Arc::clone(&styrsystem);
 
-    //Thread
-    //TODO: Move thread contents to own function: thread::spawn(move|| speedThread());
-    thread::spawn(move || {
-        loop {
-            {
-                // !!!IMPORTANT!!! scope inside the loop so that clientmutex can be unlocked between the different threads!
-                let mut client = client_clone.lock().unwrap();
-                let styrsystem = styrsystem.lock().unwrap();
-                //let data = format!("{{\"carID\":\"{}\", \"speed\": {}}}", FORDON_ID, styrsystem.get_speed());
-                let data = format!("{{\"carID\":\"{}\", \"speed\": {}}}", FORDON_ID, 5);
-                // debug!("Sending data:\n{}", data);
-                //Turned of retain so that the broker doesnt save messages between runs and
-                //sessions!
-                client
-                    .publish(
-                        "/vehicle/publishSpeed",
-                        client::QoS::AtLeastOnce,
-                        false,
-                        data.as_bytes(),
-                    )
-                    .expect("Kunde ej publisera");
-            }
-            sleep(Duration::from_secs(5));
+    //Speed for the clients listening on /vehicle/publishSpeed. The controller lock is let go before the
+    //client lock is taken, so a slow publish never holds up the control loop.
+    thread::spawn(move || loop {
+        let speed = styrsystem.lock().unwrap().get_speed();
+        let data = format!("{{\"carID\":\"{}\", \"speed\": {}}}", FORDON_ID, speed);
+        //Turned of retain so that the broker doesnt save messages between runs and
+        //sessions!
+        let published = client_clone.lock().unwrap().publish(
+            "/vehicle/publishSpeed",
+            client::QoS::AtLeastOnce,
+            false,
+            data.as_bytes(),
+        );
+        if let Err(e) = published {
+            error!("Kunde ej publicera hastighet: {}", e);
         }
+        sleep(Duration::from_secs(5));
     });
 
-    let emergency_clone = Arc::clone(&styrsys_clone);
     thread::spawn(move || {
         loop {
             {
-                let mut e = emergency_clone.lock().unwrap();
-                match e.get_emergency_stop() {
+                match estop::is_active() {
                     true => {
                         //debug!("true");
                         ws2812.set_pixel(rgb::RGB8::new(255, 0, 0)).unwrap();
@@ -139,7 +392,9 @@ fn main() {
                         ws2812.set_pixel(rgb::RGB8::new(0, 0, 255)).unwrap();
                     }
                     false => {
-                        ws2812.set_pixel(rgb::RGB8::new(0, 0, 0)).unwrap();
+                        //Colour chosen by a running block builder program, otherwise off
+                        let [red, green, blue] = executor.led().unwrap_or([0, 0, 0]);
+                        ws2812.set_pixel(rgb::RGB8::new(red, green, blue)).unwrap();
                         //debug!("False")
                     }
                 }
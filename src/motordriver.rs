@@ -0,0 +1,158 @@
+use crate::config::Config;
+use json::{object, JsonValue};
+use std::fmt;
+
+///Full scale for signed wheel duty. Positive duty drives the wheel forward, negative backward.
+pub const DUTY_MAX: i16 = 255;
+
+///The four wheels of the vehicle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wheel {
+    FrontLeft = 0,
+    FrontRight = 1,
+    BackLeft = 2,
+    BackRight = 3,
+}
+
+impl Wheel {
+    pub const ALL: [Wheel; 4] = [
+        Wheel::FrontLeft,
+        Wheel::FrontRight,
+        Wheel::BackLeft,
+        Wheel::BackRight,
+    ];
+
+    ///Index of the wheel in a `WheelDuties` array
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn is_left(&self) -> bool {
+        matches!(self, Wheel::FrontLeft | Wheel::BackLeft)
+    }
+
+    ///Name used for the wheel in JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            Wheel::FrontLeft => "frontLeft",
+            Wheel::FrontRight => "frontRight",
+            Wheel::BackLeft => "backLeft",
+            Wheel::BackRight => "backRight",
+        }
+    }
+}
+
+///Output channels a wheel motor is wired to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WheelChannels {
+    pub forward: u8,
+    pub backward: u8,
+    ///Motor is mounted or wired backwards, flips the sign of the duty
+    pub inverted: bool,
+}
+
+impl WheelChannels {
+    pub const fn new(forward: u8, backward: u8) -> Self {
+        Self {
+            forward,
+            backward,
+            inverted: false,
+        }
+    }
+}
+
+///Which channels drive which wheel, indexed by `Wheel::index`. Set when the driver is created so one firmware
+///image works with every chassis wiring.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WheelMap(pub [WheelChannels; 4]);
+
+///Wiring of the SCB Motordrive3 chassis
+impl Default for WheelMap {
+    fn default() -> Self {
+        let mut map = [WheelChannels::new(0, 0); 4];
+        map[Wheel::FrontLeft.index()] = WheelChannels::new(4, 5);
+        map[Wheel::FrontRight.index()] = WheelChannels::new(6, 7);
+        map[Wheel::BackLeft.index()] = WheelChannels::new(2, 3);
+        map[Wheel::BackRight.index()] = WheelChannels::new(0, 1);
+        Self(map)
+    }
+}
+
+impl WheelMap {
+    pub fn channels(&self, wheel: Wheel) -> WheelChannels {
+        self.0[wheel.index()]
+    }
+
+    ///Checks that every channel exists on a driver with `channel_count` outputs and is used only once
+    pub fn is_valid(&self, channel_count: u8) -> bool {
+        let mut used = 0u32;
+        for wheel in self.0 {
+            for channel in [wheel.forward, wheel.backward] {
+                if channel >= channel_count || used & (1 << channel) != 0 {
+                    return false;
+                }
+                used |= 1 << channel;
+            }
+        }
+        true
+    }
+}
+
+impl Config for WheelMap {
+    fn to_json(&self) -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for wheel in Wheel::ALL {
+            let channels = self.channels(wheel);
+            data[wheel.name()] = object! {
+                "forward" => channels.forward,
+                "backward" => channels.backward,
+                "inverted" => channels.inverted,
+            };
+        }
+        data
+    }
+
+    ///Parses `{"frontLeft": {"forward": 4, "backward": 5, "inverted": false}, ...}`. All four wheels are required,
+    ///`inverted` defaults to false.
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let mut map = Self::default();
+        for wheel in Wheel::ALL {
+            let entry = &data[wheel.name()];
+            map.0[wheel.index()] = WheelChannels {
+                forward: entry["forward"].as_u8()?,
+                backward: entry["backward"].as_u8()?,
+                inverted: entry["inverted"].as_bool().unwrap_or(false),
+            };
+        }
+        Some(map)
+    }
+}
+
+///Signed duty for every wheel, indexed by `Wheel::index`
+pub type WheelDuties = [i16; 4];
+
+///Builds a per-wheel array where both left wheels and both right wheels get the same value
+pub fn sides<T: Copy>(left: T, right: T) -> [T; 4] {
+    [left, right, left, right]
+}
+
+///Interface for boards that drive the wheel motors (PCA9634 on SCB Motordrive3, LEDC + DRV8833, TB6612...).
+///The vehicle controller only talks to the motors through this trait.
+pub trait MotorDriver {
+    type Error: fmt::Display;
+
+    ///Applies a signed duty (-DUTY_MAX..=DUTY_MAX) to all wheels at once
+    fn set_duties(&mut self, duties: WheelDuties) -> Result<(), Self::Error>;
+
+    ///Applies a signed duty (-DUTY_MAX..=DUTY_MAX) to one wheel, the others keep their duty
+    fn set_wheel(&mut self, wheel: Wheel, duty: i16) -> Result<(), Self::Error>;
+
+    ///Shorts the motor terminals so the wheels stop as fast as possible
+    fn brake(&mut self) -> Result<(), Self::Error>;
+
+    ///Releases the motors so the wheels spin freely
+    fn coast(&mut self) -> Result<(), Self::Error>;
+
+    ///Turns the motor outputs on or off. Disabled outputs ignore duties until enabled again
+    fn enable(&mut self, enabled: bool) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,101 @@
+use crate::imu::{Imu, ImuSample};
+use embedded_hal::i2c::{Error, ErrorKind, I2c};
+use std::{fmt, thread::sleep, time::Duration};
+
+///Address with AD0 low
+pub const DEFAULT_ADDRESS: u8 = 0x68;
+const WHO_AM_I_VALUE: u8 = 0x68;
+
+const SMPLRT_DIV: u8 = 0x19;
+const CONFIG: u8 = 0x1A;
+const GYRO_CONFIG: u8 = 0x1B;
+const ACCEL_CONFIG: u8 = 0x1C;
+const ACCEL_XOUT_H: u8 = 0x3B;
+const PWR_MGMT_1: u8 = 0x6B;
+const WHO_AM_I: u8 = 0x75;
+
+///±500 degrees/s
+const GYRO_RANGE: u8 = 0x08;
+const GYRO_SCALE: f32 = 65.5;
+///±8 g, room for knocks and collisions
+const ACCEL_RANGE: u8 = 0x10;
+const ACCEL_SCALE: f32 = 4096.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImuError {
+    Write(ErrorKind),
+    Read(ErrorKind),
+    ///Something else answered on the address, with its WHO_AM_I
+    WrongDevice(u8),
+}
+
+impl fmt::Display for ImuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImuError::Write(kind) => write!(f, "I2C-fel vid skrivning till IMU: {:?}", kind),
+            ImuError::Read(kind) => write!(f, "I2C-fel vid läsning av IMU: {:?}", kind),
+            ImuError::WrongDevice(id) => write!(f, "Okänd IMU, WHO_AM_I {:02x}", id),
+        }
+    }
+}
+
+impl std::error::Error for ImuError {}
+
+///InvenSense MPU-6050 gyro and accelerometer
+pub struct Mpu6050<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Mpu6050<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+
+    ///Resets the chip and sets 100 Hz sampling with a 44 Hz low-pass filter
+    pub fn init(&mut self) -> Result<(), ImuError> {
+        let id = self.read_register(WHO_AM_I)?;
+        if id != WHO_AM_I_VALUE {
+            return Err(ImuError::WrongDevice(id));
+        }
+        self.write_register(PWR_MGMT_1, 0x80)?;
+        sleep(Duration::from_millis(100));
+        //Wake up, clocked from the x gyro
+        self.write_register(PWR_MGMT_1, 0x01)?;
+        self.write_register(SMPLRT_DIV, 9)?;
+        self.write_register(CONFIG, 0x03)?;
+        self.write_register(GYRO_CONFIG, GYRO_RANGE)?;
+        self.write_register(ACCEL_CONFIG, ACCEL_RANGE)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), ImuError> {
+        self.i2c
+            .write(self.address, &[register, value])
+            .map_err(|e| ImuError::Write(e.kind()))
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, ImuError> {
+        let mut data = [0];
+        self.i2c
+            .write_read(self.address, &[register], &mut data)
+            .map_err(|e| ImuError::Read(e.kind()))?;
+        Ok(data[0])
+    }
+}
+
+impl<I2C: I2c> Imu for Mpu6050<I2C> {
+    type Error = ImuError;
+
+    ///Reads accelerometer, temperature and gyro in one burst
+    fn read(&mut self) -> Result<ImuSample, ImuError> {
+        let mut data = [0u8; 14];
+        self.i2c
+            .write_read(self.address, &[ACCEL_XOUT_H], &mut data)
+            .map_err(|e| ImuError::Read(e.kind()))?;
+        let value = |i: usize| i16::from_be_bytes([data[i], data[i + 1]]) as f32;
+        Ok(ImuSample {
+            accel: [0, 2, 4].map(|i| value(i) / ACCEL_SCALE),
+            gyro: [8, 10, 12].map(|i| value(i) / GYRO_SCALE),
+        })
+    }
+}
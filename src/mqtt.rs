@@ -1,4 +1,25 @@
-use crate::controllerhal::PCA9634;
+use crate::battery::BatteryConfig;
+use crate::calibration::Calibration;
+use crate::calibrator::CalibrationSession;
+use crate::collision::CollisionConfig;
+use crate::config::Config;
+use crate::controllerhal;
+use crate::dutycurve::DutyCurve;
+use crate::estop;
+use crate::events::Publisher;
+use crate::executor::{self, Executor};
+use crate::imu::HeadingConfig;
+use crate::joystick::JoystickConfig;
+use crate::motordriver::{Wheel, WheelMap};
+use crate::overload::OverloadConfig;
+use crate::pose::Pose;
+use crate::program::Program;
+use crate::programstore::{ProgramStore, StoreError};
+use crate::ramp::RampConfig;
+use crate::safety::SafetyConfig;
+use crate::settings::{Settings, Stored, MIN_AUTORUN_DELAY};
+use crate::speedcontrol::SpeedControlConfig;
+use crate::Styrsystem;
 use embedded_svc::mqtt::client::QoS;
 use embedded_svc::{
     io::ErrorKind,
@@ -11,10 +32,11 @@ use esp_idf_svc::{
     hal::i2c::{I2cConfig, I2cDriver},
     mqtt::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration},
 };
-use json::{self, JsonValue};
-use log::debug;
+use json::{self, object, JsonValue};
+use log::{debug, error};
 use serde_json::{from_slice, from_str, Value};
 use std::{
+    fmt,
     os::unix::net::UnixDatagram,
     str::FromStr,
     sync::{Arc, Mutex},
@@ -24,20 +46,37 @@ use std::{
 
 pub fn mqtt_init(
     mqttadr: &str,
-    styrsystem: Arc<Mutex<PCA9634<I2cDriver<'static>>>>,
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    settings: Arc<Mutex<Settings>>,
+    store: Arc<Mutex<ProgramStore>>,
+    executor: Executor,
+    publisher: Publisher,
     carid: &str,
 ) -> EspMqttClient<'static> {
     esp_idf_sys::link_patches();
 
     let mqtt_config = MqttClientConfiguration::default();
     let carid = carid.to_owned();
+    //Guided calibration in progress, only touched from the MQTT callback
+    let mut calibration: Option<CalibrationSession> = None;
     // Creates client and definition of event
     let client = EspMqttClient::new(mqttadr, &mqtt_config, move |message_event| {
         let styrsystem = Arc::clone(&styrsystem);
+        let settings = Arc::clone(&settings);
+        let store = Arc::clone(&store);
         match message_event.as_ref().unwrap() {
             Event::Connected(_) => debug!("Connected"),
             Event::Subscribed(id) => debug!("Subscribed to {} id", id),
-            Event::Received(msg) => handle_message(msg, styrsystem, &carid),
+            Event::Received(msg) => handle_message(
+                msg,
+                styrsystem,
+                settings,
+                store,
+                &mut calibration,
+                &executor,
+                &publisher,
+                &carid,
+            ),
             Event::Published(msg) => (),
             _ => debug!("{:?}", message_event.as_ref().unwrap()),
         };
@@ -49,29 +88,191 @@ pub fn mqtt_init(
 /// TODO: Add handling depending on topic and ID. Preferrably in different functions or such.
 fn handle_message(
     msg: &EspMqttMessage,
-    styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>,
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    settings: Arc<Mutex<Settings>>,
+    store: Arc<Mutex<ProgramStore>>,
+    calibration: &mut Option<CalibrationSession>,
+    executor: &Executor,
+    publisher: &Publisher,
     carid: &str,
 ) {
     match msg.topic() {
-        Some("/user/setSpeed") => set_vehicle_speed(msg.data(), styrsystem, &carid),
-        Some("/user/maxSpeed") => set_max_speed(msg.data(), styrsystem, &carid),
-        Some("/user/emergencyStop") => emergency_stop_id(msg.data(), styrsystem, &carid),
-        Some("/user/emergencyStopAll") => emergency_stop(msg.data(), styrsystem),
+        Some("/user/setSpeed") => {
+            set_vehicle_speed(msg.data(), styrsystem, executor, publisher, &carid)
+        }
+        Some("/user/maxSpeed") => set_max_speed(msg.data(), styrsystem, publisher, &carid),
+        Some("/user/emergencyStop") => emergency_stop_id(msg.data(), &carid),
+        Some("/user/emergencyStopAll") => emergency_stop(msg.data()),
         //keyboard commands
-        Some("/user/keyboard") => keyboard(msg.data(), styrsystem, carid),
-        Some("/user/blockbuilder") => instructions(msg.data(), styrsystem, carid),
+        Some("/user/keyboard") => keyboard(msg.data(), styrsystem, executor, publisher, carid),
+        Some("/user/twist") => twist(msg.data(), styrsystem, executor, publisher, carid),
+        Some("/user/joystick") => joystick(msg.data(), styrsystem, executor, publisher, carid),
+        Some("/user/blockbuilder") => instructions(msg.data(), styrsystem, executor, carid),
+        Some("/user/program") => {
+            program_control(msg.data(), styrsystem, executor, publisher, carid)
+        }
+        Some("/user/pose") => pose(msg.data(), styrsystem, carid),
+        Some("/user/programs") => stored_programs(
+            msg.data(),
+            styrsystem,
+            settings,
+            store,
+            executor,
+            publisher,
+            carid,
+        ),
+        //vehicle configuration
+        Some("/user/wheelMap") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            |data| {
+                WheelMap::from_json(&data["map"])
+                    .filter(|map| map.is_valid(controllerhal::CHANNELS))
+            },
+            |styrsystem, map| {
+                if let Err(e) = styrsystem.driver().set_wheel_map(map) {
+                    driver_error(publisher, "wheelMap", e);
+                }
+                Ok(())
+            },
+        ),
+        Some("/user/dutyCurve") => set_duty_curve(msg.data(), styrsystem, settings, carid),
+        Some("/user/ramp") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            RampConfig::from_json,
+            |styrsystem, config| {
+                styrsystem.set_ramp_config(config);
+                Ok(())
+            },
+        ),
+        Some("/user/joystickConfig") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            JoystickConfig::from_json,
+            |styrsystem, config| {
+                styrsystem.set_joystick_config(config);
+                Ok(())
+            },
+        ),
+        Some("/user/deadman") => set_deadman(msg.data(), styrsystem, settings, carid),
+        Some("/user/calibration") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            Calibration::from_json,
+            |styrsystem, calibration| {
+                styrsystem.set_calibration(calibration);
+                Ok(())
+            },
+        ),
+        Some("/user/speedControl") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            SpeedControlConfig::from_json,
+            |styrsystem, config| {
+                styrsystem
+                    .set_speed_control_config(config)
+                    .map_err(String::from)
+            },
+        ),
+        Some("/user/headingControl") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            HeadingConfig::from_json,
+            |styrsystem, config| {
+                styrsystem.set_heading_config(config);
+                Ok(())
+            },
+        ),
+        Some("/user/safety") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            SafetyConfig::from_json,
+            |styrsystem, config| {
+                styrsystem.set_safety_config(config);
+                Ok(())
+            },
+        ),
+        Some("/user/collision") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            CollisionConfig::from_json,
+            |styrsystem, config| {
+                styrsystem.set_collision_config(config);
+                Ok(())
+            },
+        ),
+        Some("/user/battery") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            BatteryConfig::from_json,
+            |styrsystem, config| {
+                if let Err(e) = styrsystem.set_battery_config(config) {
+                    driver_error(publisher, "battery", e);
+                }
+                Ok(())
+            },
+        ),
+        Some("/user/overload") => set_config(
+            msg.data(),
+            styrsystem,
+            settings,
+            publisher,
+            carid,
+            OverloadConfig::from_json,
+            |styrsystem, config| {
+                styrsystem.set_overload_config(config);
+                Ok(())
+            },
+        ),
+        Some("/user/calibrate") => calibrate(
+            msg.data(),
+            styrsystem,
+            settings,
+            calibration,
+            executor,
+            publisher,
+            carid,
+        ),
         _ => {}
     }
 }
 //Gets value (boolean) from mqtt-emergency stop.
-//Sende value to controller and puts actual value to the vehicle.
-fn emergency_stop(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>) {
+//Sets the emergency stop flag without waiting for the controller lock, the control loop cuts the motors.
+fn emergency_stop(data: &[u8]) {
     match convert_to_json(data) {
         Ok(jsondata) => {
-            let car_state = jsondata.as_bool().unwrap();
-            {
-                let mut styrsystem = styrsystem.lock().unwrap();
-                styrsystem.set_emergency_stop(car_state);
+            if let Some(car_state) = jsondata.as_bool() {
+                set_emergency_stop(car_state);
+            } else {
+                debug!("kunde ej konvertera nödstopp till bool");
             }
         }
         Err(e) => {
@@ -80,14 +281,13 @@ fn emergency_stop(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>) {
     }
 }
 
-fn emergency_stop_id(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, carid: &str) {
+fn emergency_stop_id(data: &[u8], carid: &str) {
     match convert_to_json(data) {
         Ok(jsondata) => {
             if let Some(id) = jsondata["carID"].as_str() {
                 if id == carid {
                     if let Some(emstop) = jsondata["state"].as_bool() {
-                        let mut styrsystem = styrsystem.lock().unwrap();
-                        styrsystem.set_emergency_stop(emstop);
+                        set_emergency_stop(emstop);
                     } else {
                         debug!("kunde ej konvertera speed till sträng");
                     }
@@ -101,8 +301,31 @@ fn emergency_stop_id(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>
         }
     };
 }
+fn set_emergency_stop(state: bool) {
+    if state {
+        estop::trigger();
+    } else {
+        estop::release();
+    }
+}
+
+///Reports a command the motor driver failed to carry out as a `driverError` event
+fn driver_error(publisher: &Publisher, command: &str, e: impl fmt::Display) {
+    error!("{}: {}", command, e);
+    publisher.event(
+        "driverError",
+        object! { "command" => command, "error" => e.to_string() },
+    );
+}
+
 ///Sets the speed of the vehicle
-fn set_vehicle_speed(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, carid: &str) {
+fn set_vehicle_speed(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    executor: &Executor,
+    publisher: &Publisher,
+    carid: &str,
+) {
     match convert_to_json(data) {
         Ok(jsondata) => {
             if let Some(id) = jsondata["carID"].as_str() {
@@ -114,9 +337,14 @@ fn set_vehicle_speed(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>
                             Ok(speed) => {
                                 if speed <= 100 && speed >= -100 {
                                     {
+                                        executor.cancel();
                                         let mut styrsystem = styrsystem.lock().unwrap();
-                                        styrsystem.set_speed(speed);
-                                        styrsystem.read_all_addresses();
+                                        if let Err(e) = styrsystem
+                                            .set_speed(speed)
+                                            .and_then(|_| styrsystem.driver().read_all_addresses())
+                                        {
+                                            driver_error(publisher, "setSpeed", e);
+                                        }
                                     }
 
                                     debug!("Tog emot meddelande!");
@@ -141,7 +369,12 @@ fn set_vehicle_speed(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>
         }
     };
 }
-fn set_max_speed(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, carid: &str) {
+fn set_max_speed(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    publisher: &Publisher,
+    carid: &str,
+) {
     match convert_to_json(data) {
         Ok(jsondata) => {
             if let Some(id) = jsondata["carID"].as_str() {
@@ -151,7 +384,9 @@ fn set_max_speed(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, ca
                             Ok(maxspeed) => {
                                 if maxspeed <= 100 && maxspeed >= -100 {
                                     let mut styrsystem = styrsystem.lock().unwrap();
-                                    styrsystem.set_max_speed(maxspeed);
+                                    if let Err(e) = styrsystem.set_max_speed(maxspeed) {
+                                        driver_error(publisher, "maxSpeed", e);
+                                    }
                                 }
                             }
                             Err(_) => {
@@ -172,6 +407,286 @@ fn set_max_speed(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, ca
     };
 }
 
+///Parses a config sent to the vehicle with `parse`, hands it to `apply` and saves it to NVS. A config the
+///vehicle refuses is not saved. The outcome is published as a `configApplied` or `configRejected` event.
+fn set_config<C: Stored + Clone>(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    settings: Arc<Mutex<Settings>>,
+    publisher: &Publisher,
+    carid: &str,
+    parse: impl FnOnce(&JsonValue) -> Option<C>,
+    apply: impl FnOnce(&mut Styrsystem, C) -> Result<(), String>,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    let applied = match parse(&jsondata) {
+                        Some(config) => {
+                            let mut styrsystem = styrsystem.lock().unwrap();
+                            apply(&mut styrsystem, config.clone()).map(|_| config)
+                        }
+                        None => Err(format!("Ogiltiga värden för {}", C::NAME)),
+                    };
+                    match applied {
+                        Ok(config) => {
+                            let mut settings = settings.lock().unwrap();
+                            let saved = match settings.set_config(&config) {
+                                Ok(_) => true,
+                                Err(e) => {
+                                    error!("Kunde ej spara {}: {}", C::NAME, e);
+                                    false
+                                }
+                            };
+                            publisher.event(
+                                "configApplied",
+                                object! { "config" => C::KEY, "saved" => saved },
+                            );
+                        }
+                        Err(e) => {
+                            debug!("{}", e);
+                            publisher.event(
+                                "configRejected",
+                                object! { "config" => C::KEY, "error" => e },
+                            );
+                        }
+                    }
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
+///Changes the duty curve for one or all wheels and one or both directions, applies it and saves it to NVS.
+///`wheel` is a wheel name or "all", `direction` is "forward", "backward" or "both". Both default to all.
+fn set_duty_curve(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    settings: Arc<Mutex<Settings>>,
+    carid: &str,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    let wheels: Vec<Wheel> = match jsondata["wheel"].as_str().unwrap_or("all") {
+                        "all" => Wheel::ALL.to_vec(),
+                        name => Wheel::ALL
+                            .into_iter()
+                            .filter(|w| w.name() == name)
+                            .collect(),
+                    };
+                    let (forward, backward) = match jsondata["direction"].as_str().unwrap_or("both")
+                    {
+                        "forward" => (true, false),
+                        "backward" => (false, true),
+                        _ => (true, true),
+                    };
+                    if wheels.is_empty() {
+                        debug!("Okänt hjul!");
+                    } else if let Some(curve) = DutyCurve::from_json(&jsondata["curve"]) {
+                        let curves = {
+                            let mut styrsystem = styrsystem.lock().unwrap();
+                            let mut curves = styrsystem.get_duty_curves().clone();
+                            curves.set(&wheels, forward, backward, &curve);
+                            styrsystem.set_duty_curves(curves.clone());
+                            curves
+                        };
+                        let mut settings = settings.lock().unwrap();
+                        if let Err(e) = settings.set_config(&curves) {
+                            error!("Kunde ej spara dutykurvor: {}", e);
+                        }
+                    } else {
+                        debug!("Ogiltig dutykurva!");
+                    }
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
+///Guided calibration. `start` plans a straight run and a spin at each speed, `run` drives the next one when
+///the vehicle is in place and `measure` reports how far it got. When every run is measured the model is
+///fitted, used and saved to NVS. `get` publishes the current model and `cancel` ends the calibration.
+///Progress and results are published on /vehicle/<carID>/calibration.
+fn calibrate(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    settings: Arc<Mutex<Settings>>,
+    session: &mut Option<CalibrationSession>,
+    executor: &Executor,
+    publisher: &Publisher,
+    carid: &str,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    let result = calibration_command(
+                        &jsondata,
+                        &styrsystem,
+                        &settings,
+                        session,
+                        executor,
+                        publisher,
+                    );
+                    if let Err(e) = result {
+                        debug!("{}", e);
+                        publisher.event_on(
+                            "calibration",
+                            "calibrationError",
+                            object! { "error" => e },
+                        );
+                    }
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
+fn calibration_command(
+    jsondata: &JsonValue,
+    styrsystem: &Mutex<Styrsystem>,
+    settings: &Mutex<Settings>,
+    session: &mut Option<CalibrationSession>,
+    executor: &Executor,
+    publisher: &Publisher,
+) -> Result<(), String> {
+    let report = |kind: &str, fields: JsonValue| publisher.event_on("calibration", kind, fields);
+    match jsondata["command"].as_str() {
+        Some("start") => {
+            let (current, heading) = {
+                let styrsystem = styrsystem.lock().unwrap();
+                let heading =
+                    styrsystem.get_heading_config().enabled && styrsystem.get_heading().is_some();
+                (styrsystem.get_calibration().clone(), heading)
+            };
+            //Spins closed on the heading stop at the angle asked for, there is nothing to measure
+            if heading {
+                return Err("Stäng av kursregleringen under kalibreringen".to_owned());
+            }
+            let started = CalibrationSession::from_json(jsondata, &current)?;
+            report("calibrationStarted", started.to_json());
+            if let Some(next) = started.next() {
+                report("calibrationNext", started.trials()[next].to_json(next));
+            }
+            *session = Some(started);
+        }
+        Some("run") => {
+            let running = session.as_mut().ok_or("Ingen kalibrering pågår")?;
+            let (index, speed, program, estimate) = {
+                let styrsystem = styrsystem.lock().unwrap();
+                let cap = |speed| styrsystem.capped_speed(speed);
+                let calibration = styrsystem.get_calibration();
+                let (index, speed, program) = running
+                    .plan_next(calibration, cap)
+                    .ok_or("Alla körningar är mätta")?;
+                let estimate = program
+                    .estimate(calibration, cap)
+                    .map_err(|e| e.to_string())?;
+                (index, speed, program, estimate)
+            };
+            //Runs like any program, so live control, abort and the emergency stop stop it
+            executor.submit(program, estimate, Some("calibration".to_owned()), true)?;
+            running.mark_run(index, speed);
+            report("calibrationRun", running.trials()[index].to_json(index));
+        }
+        Some("measure") => {
+            let running = session.as_mut().ok_or("Ingen kalibrering pågår")?;
+            let value = jsondata["value"].as_f32().ok_or("Mätvärde saknas")?;
+            let index = running.measure(value)?;
+            report(
+                "calibrationMeasured",
+                running.trials()[index].to_json(index),
+            );
+            if let Some(next) = running.next() {
+                report("calibrationNext", running.trials()[next].to_json(next));
+                return Ok(());
+            }
+            let calibration = running.fit().ok_or("Mätningarna ger ingen giltig modell")?;
+            let mut result = calibration.to_json();
+            result["trials"] = running.to_json()["trials"].take();
+            styrsystem
+                .lock()
+                .unwrap()
+                .set_calibration(calibration.clone());
+            if let Err(e) = settings.lock().unwrap().set_config(&calibration) {
+                error!("Kunde ej spara kalibrering: {}", e);
+            }
+            report("calibrationResult", result);
+            *session = None;
+        }
+        Some("get") => {
+            let current = styrsystem.lock().unwrap().get_calibration().to_json();
+            report("calibration", current);
+        }
+        Some("cancel") => {
+            if session.take().is_some() {
+                executor.cancel();
+                styrsystem
+                    .lock()
+                    .unwrap()
+                    .stop_vehicle()
+                    .map_err(|e| e.to_string())?;
+            }
+            report("calibrationCancelled", object! {});
+        }
+        _ => debug!("Okänt kalibreringskommando!"),
+    }
+    Ok(())
+}
+
+///Changes the deadman window (ms) for live control and saves it to NVS. 0 turns the deadman off.
+fn set_deadman(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    settings: Arc<Mutex<Settings>>,
+    carid: &str,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    if let Some(timeout) = jsondata["timeout"].as_u64() {
+                        let timeout = Duration::from_millis(timeout);
+                        {
+                            let mut styrsystem = styrsystem.lock().unwrap();
+                            styrsystem.set_deadman_timeout(timeout);
+                        }
+                        let mut settings = settings.lock().unwrap();
+                        if let Err(e) = settings.set_deadman_timeout(timeout) {
+                            error!("Kunde ej spara deadman-tid: {}", e);
+                        }
+                    } else {
+                        debug!("Kunde ej parsera deadman-tid!");
+                    }
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
 fn parse_json_to_i32(data: Option<&str>) -> i32 {
     match data {
         Some(data) => {
@@ -212,7 +727,13 @@ fn convert_to_json(data: &[u8]) -> Result<JsonValue, &str> {
 }
 
 //Keyboard controll
-fn keyboard(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, carid: &str) {
+fn keyboard(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    executor: &Executor,
+    publisher: &Publisher,
+    carid: &str,
+) {
     debug!("keyboard command");
     match convert_to_json(data) {
         Ok(jsondata) => {
@@ -222,8 +743,12 @@ fn keyboard(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, carid:
                         if let Some(speed) = jsondata["speed"].as_i32() {
                             if let Some(direction) = jsondata["direction"].as_i32() {
                                 debug!("keyboard: {state}, {speed}, {direction}");
+                                executor.cancel();
                                 let mut styrsystem = styrsystem.lock().unwrap();
-                                styrsystem.keyboard_control(direction, state, speed);
+                                if let Err(e) = styrsystem.keyboard_control(direction, state, speed)
+                                {
+                                    driver_error(publisher, "keyboard", e);
+                                }
                             } else {
                                 debug!("direction error");
                             }
@@ -244,36 +769,288 @@ fn keyboard(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, carid:
     };
 }
 
-fn instructions(data: &[u8], styrsystem: Arc<Mutex<PCA9634<I2cDriver<'_>>>>, carid: &str) {
-    debug!("keyboard command");
-    println!("------ Instruktion kommando -----");
-    let jsondata: Value = from_slice(data).expect("Kunde ej parsera JSON");
-    if let Some(id) = jsondata["id"].as_str() {
-    println!("mottaget id: {id} carid: {carid}");
-        if id == carid {
-            if let Some(instructions) = jsondata["instructions"].as_array() {
-                for instruction in instructions {
-                    println!("Instruktion!");                    
-                    if let Some(meters) = instruction["forward"].as_i64() {
-                        println!("Forward start!");
-                        let mut styrsystem = styrsystem.lock().unwrap();
-                        styrsystem.inst_forward(meters as i32);
-                    } else if let Some(meters) = instruction["backward"].as_i64() {
-                         println!("Backward start!");
-                         let mut styrsystem = styrsystem.lock().unwrap();
-                        styrsystem.inst_backward(meters as i32);
-                    } else if let Some(rotatel) = instruction["rotateL"].as_i64() {
-                        println!("Rotate Left start!");
-                        let mut styrsystem = styrsystem.lock().unwrap();
-                        styrsystem.inst_rotate_l(rotatel as i32);
-                    } else if let Some(rotater) = instruction["rotateR"].as_i64() {
-                        println!("Rotate Right start!");
-                        let mut styrsystem = styrsystem.lock().unwrap();
-                        styrsystem.inst_rotate_r(rotater as i32);
+///Continuous control with linear and angular speed (-100..100 each)
+fn twist(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    executor: &Executor,
+    publisher: &Publisher,
+    carid: &str,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    match (jsondata["linear"].as_f32(), jsondata["angular"].as_f32()) {
+                        (Some(linear), Some(angular)) => {
+                            if (-100.0..=100.0).contains(&linear)
+                                && (-100.0..=100.0).contains(&angular)
+                            {
+                                executor.cancel();
+                                let mut styrsystem = styrsystem.lock().unwrap();
+                                if let Err(e) = styrsystem.set_twist(linear, angular) {
+                                    driver_error(publisher, "twist", e);
+                                }
+                            } else {
+                                debug!("Twist är utanför tillåten räckvid (-100 - 100)!");
+                            }
+                        }
+                        _ => debug!("Kunde ej parsera twist!"),
+                    }
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
+///Analog stick control with normalized `x`/`y` (-1..1) and an optional `throttle` (0..1, default 1)
+fn joystick(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    executor: &Executor,
+    publisher: &Publisher,
+    carid: &str,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    let throttle = if jsondata["throttle"].is_null() {
+                        Some(1.0)
+                    } else {
+                        jsondata["throttle"].as_f32()
+                    };
+                    match (jsondata["x"].as_f32(), jsondata["y"].as_f32(), throttle) {
+                        (Some(x), Some(y), Some(throttle)) => {
+                            if (-1.0..=1.0).contains(&x)
+                                && (-1.0..=1.0).contains(&y)
+                                && (0.0..=1.0).contains(&throttle)
+                            {
+                                executor.cancel();
+                                let mut styrsystem = styrsystem.lock().unwrap();
+                                if let Err(e) = styrsystem.joystick_control(x, y, throttle) {
+                                    driver_error(publisher, "joystick", e);
+                                }
+                            } else {
+                                debug!("Joystick är utanför tillåten räckvid!");
+                            }
+                        }
+                        _ => debug!("Kunde ej parsera joystick!"),
+                    }
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
+///Validates a block builder program and hands it to the motion executor. A new program replaces the running
+///one unless `"queue": true` is given. With `"dryRun": true` only the estimate is published. Progress is
+///published on /vehicle/<carID>/program, tagged with `correlationId` from the request.
+fn instructions(data: &[u8], styrsystem: Arc<Mutex<Styrsystem>>, executor: &Executor, carid: &str) {
+    debug!("------ Instruktion kommando -----");
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["id"].as_str() {
+                debug!("mottaget id: {} carid: {}", id, carid);
+                if id == carid {
+                    executor.submit_json(
+                        &styrsystem,
+                        &jsondata,
+                        jsondata["correlationId"].as_str(),
+                        jsondata["dryRun"].as_bool().unwrap_or(false),
+                        !jsondata["queue"].as_bool().unwrap_or(false),
+                    );
+                }
+            } else {
+                debug!("fel på id");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
+///Programs stored on the vehicle. `command` is `upload` (with `name` and `instructions`), `list`, `delete`,
+///`run` (with `dryRun` and `queue` as for the block builder) or `autorun` (`name` and `delay` in ms, no name
+///turns it off).
+fn stored_programs(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    settings: Arc<Mutex<Settings>>,
+    store: Arc<Mutex<ProgramStore>>,
+    executor: &Executor,
+    publisher: &Publisher,
+    carid: &str,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    let correlation = jsondata["correlationId"].as_str();
+                    let report = |kind: &str, fields: JsonValue| {
+                        executor::report(publisher, correlation, kind, fields)
+                    };
+                    let name = jsondata["name"].as_str().unwrap_or("");
+                    let result = match jsondata["command"].as_str() {
+                        Some("upload") => match Program::from_json(&jsondata) {
+                            Ok(_) => {
+                                let program =
+                                    object! { "instructions" => jsondata["instructions"].clone() };
+                                let mut store = store.lock().unwrap();
+                                store
+                                    .save(name, &program)
+                                    .map(|_| report("programStored", object! { "name" => name }))
+                            }
+                            Err(e) => {
+                                report("programRejected", e.to_json());
+                                Ok(())
+                            }
+                        },
+                        Some("list") => {
+                            let store = store.lock().unwrap();
+                            report("programList", object! { "programs" => store.list_json() });
+                            Ok(())
+                        }
+                        Some("delete") => {
+                            let mut store = store.lock().unwrap();
+                            store
+                                .delete(name)
+                                .map(|_| report("programDeleted", object! { "name" => name }))
+                        }
+                        Some("run") => {
+                            let program = store.lock().unwrap().load(name);
+                            program.map(|program| {
+                                executor.submit_json(
+                                    &styrsystem,
+                                    &program,
+                                    correlation,
+                                    jsondata["dryRun"].as_bool().unwrap_or(false),
+                                    !jsondata["queue"].as_bool().unwrap_or(false),
+                                )
+                            })
+                        }
+                        Some("autorun") if name.is_empty() => {
+                            let mut settings = settings.lock().unwrap();
+                            settings
+                                .set_autorun(None)
+                                .map_err(StoreError::Nvs)
+                                .map(|_| report("autorunSet", object! {}))
+                        }
+                        Some("autorun") => store.lock().unwrap().load(name).and_then(|_| {
+                            let delay = jsondata["delay"].as_u64().unwrap_or(0);
+                            let delay = Duration::from_millis(delay).max(MIN_AUTORUN_DELAY);
+                            settings.lock().unwrap().set_autorun(Some((name, delay)))?;
+                            report(
+                                "autorunSet",
+                                object! { "name" => name, "delay" => delay.as_millis() as u64 },
+                            );
+                            Ok(())
+                        }),
+                        _ => {
+                            debug!("Okänt programkommando!");
+                            Ok(())
+                        }
+                    };
+                    if let Err(e) = result {
+                        debug!("{}", e);
+                        report(
+                            "programStoreError",
+                            object! { "name" => name, "error" => e.to_string() },
+                        );
                     }
                 }
-            } else{ println!("fel på as array"); }
+            } else {
+                debug!("ID matchar ej.");
+            }
         }
-    } else{println!("fel på id");}
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
 }
 
+///Controls the running block builder program: `pause`, `resume`, `abort`, `step` or `status`. Every command is
+///answered with a `programStatus` event holding the block being run and if the program is paused.
+fn program_control(
+    data: &[u8],
+    styrsystem: Arc<Mutex<Styrsystem>>,
+    executor: &Executor,
+    publisher: &Publisher,
+    carid: &str,
+) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    match jsondata["command"].as_str() {
+                        Some("pause") => executor.pause(),
+                        Some("resume") => executor.resume(),
+                        Some("step") => executor.step(),
+                        Some("abort") => {
+                            executor.cancel();
+                            let mut styrsystem = styrsystem.lock().unwrap();
+                            if let Err(e) = styrsystem.stop_vehicle() {
+                                driver_error(publisher, "program", e);
+                            }
+                        }
+                        Some("status") => {}
+                        _ => {
+                            debug!("Okänt programkommando!");
+                            return;
+                        }
+                    }
+                    executor::report(
+                        publisher,
+                        jsondata["correlationId"].as_str(),
+                        "programStatus",
+                        object! {
+                            "index" => executor.current_instruction(),
+                            "paused" => executor.is_paused(),
+                        },
+                    );
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
+
+///Resets the dead-reckoning pose to the origin, or sets it to where the vehicle was seen
+fn pose(data: &[u8], styrsystem: Arc<Mutex<Styrsystem>>, carid: &str) {
+    match convert_to_json(data) {
+        Ok(jsondata) => {
+            if let Some(id) = jsondata["carID"].as_str() {
+                if id == carid {
+                    match jsondata["command"].as_str() {
+                        Some("reset") => styrsystem.lock().unwrap().reset_pose(),
+                        Some("set") => match Pose::from_json(&jsondata) {
+                            Some(pose) => styrsystem.lock().unwrap().set_pose(pose),
+                            None => debug!("Ogiltig position!"),
+                        },
+                        _ => debug!("Okänt positionskommando!"),
+                    }
+                }
+            } else {
+                debug!("ID matchar ej.");
+            }
+        }
+        Err(e) => {
+            debug!("{}", e);
+        }
+    };
+}
@@ -0,0 +1,360 @@
+use crate::config::Config;
+use crate::vehicle::WheelSpeeds;
+use json::{object, JsonValue};
+use std::fmt;
+use std::time::Duration;
+
+///Time to ramp a held-back output between full speed and nothing, down on a stall and up after the cooldown
+const RAMP_TIME: Duration = Duration::from_millis(500);
+
+///One reading from a power monitor on the motor supply
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PowerSample {
+    ///Supply voltage (V)
+    pub voltage: f32,
+    ///Current drawn by the motors (A)
+    pub current: f32,
+    ///Power drawn by the motors (W)
+    pub power: f32,
+}
+
+///Interface for power monitors on the motor supply (INA219...). The control loop reads it every tick and
+///hands the samples to the vehicle controller.
+pub trait PowerMonitor {
+    type Error: fmt::Display;
+
+    fn read(&mut self) -> Result<PowerSample, Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OverloadConfig {
+    ///Protects the motors when a power monitor is fitted
+    pub enabled: bool,
+    ///Current (A) that means a stalled motor when it lasts
+    pub stall_current: f32,
+    ///How long the stall current may last before the stalled outputs are ramped down
+    pub stall_time: Duration,
+    ///Current (A) that cuts all outputs at once
+    pub limit: f32,
+    ///How long the outputs stay off before they are let back on
+    pub cooldown: Duration,
+}
+
+impl Default for OverloadConfig {
+    ///For the 3.2 A range of an INA219 with a 0.1 ohm shunt
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stall_current: 1.5,
+            stall_time: Duration::from_millis(500),
+            limit: 3.0,
+            cooldown: Duration::from_millis(2000),
+        }
+    }
+}
+
+impl OverloadConfig {
+    pub fn is_valid(&self) -> bool {
+        self.stall_current > 0.0 && self.limit > self.stall_current
+    }
+}
+
+impl Config for OverloadConfig {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "enabled" => self.enabled,
+            "stallCurrent" => self.stall_current,
+            "stallTime" => self.stall_time.as_millis() as u64,
+            "limit" => self.limit,
+            "cooldown" => self.cooldown.as_millis() as u64,
+        }
+    }
+
+    ///Parses `{"enabled": true, "stallCurrent": 1.5, "stallTime": 500, "limit": 3.0, "cooldown": 2000}`,
+    ///times in milliseconds
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            enabled: data["enabled"].as_bool()?,
+            stall_current: data["stallCurrent"].as_f32()?,
+            stall_time: Duration::from_millis(data["stallTime"].as_u64()?),
+            limit: data["limit"].as_f32()?,
+            cooldown: Duration::from_millis(data["cooldown"].as_u64()?),
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
+
+///What the overload protection is doing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverloadState {
+    ///Current within limits, full output
+    Normal,
+    ///A motor has drawn the stall current too long, its output is ramped down and held off
+    Stalled,
+    ///The current limit was passed, all outputs are off
+    Overcurrent,
+}
+
+impl OverloadState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverloadState::Normal => "normal",
+            OverloadState::Stalled => "stalled",
+            OverloadState::Overcurrent => "overcurrent",
+        }
+    }
+}
+
+///Stall and overcurrent protection on the motor supply current. Time is passed in with every sample, so
+///the detection can be run against recorded or simulated current traces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverloadGuard {
+    config: OverloadConfig,
+    state: OverloadState,
+    //Tid strömmen legat över stallgränsen
+    stall_for: Duration,
+    //Tid utgångarna har varit avstängda
+    off_for: Duration,
+    //Andel av farten som släpps igenom till de drabbade hjulen
+    scale: f32,
+    //Hjul som begränsas
+    affected: [bool; 4],
+    //Högsta ström sedan skyddet löste ut
+    peak: f32,
+}
+
+impl OverloadGuard {
+    pub fn new(config: OverloadConfig) -> Self {
+        Self {
+            config,
+            state: OverloadState::Normal,
+            stall_for: Duration::ZERO,
+            off_for: Duration::ZERO,
+            scale: 1.0,
+            affected: [false; 4],
+            peak: 0.0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: OverloadConfig) {
+        self.config = config;
+        self.release();
+    }
+
+    pub fn get_config(&self) -> OverloadConfig {
+        self.config
+    }
+
+    ///Adds a current sample (A) taken `dt` after the last one. `driving` tells if any output is on,
+    ///`stalled` which wheels a stall is put down to. Returns the new state when it changes.
+    pub fn update(
+        &mut self,
+        current: f32,
+        dt: Duration,
+        driving: bool,
+        stalled: [bool; 4],
+    ) -> Option<OverloadState> {
+        if !self.config.enabled {
+            let changed = self.state != OverloadState::Normal;
+            self.release();
+            self.recover(dt);
+            return changed.then_some(OverloadState::Normal);
+        }
+        if current >= self.config.limit && self.state != OverloadState::Overcurrent {
+            self.trip(OverloadState::Overcurrent, [true; 4], current);
+            self.scale = 0.0;
+            return Some(self.state);
+        }
+        match self.state {
+            OverloadState::Normal => {
+                self.recover(dt);
+                if driving && current >= self.config.stall_current {
+                    self.stall_for += dt;
+                    if self.stall_for >= self.config.stall_time {
+                        self.trip(OverloadState::Stalled, stalled, current);
+                        return Some(self.state);
+                    }
+                } else {
+                    self.stall_for = Duration::ZERO;
+                }
+                None
+            }
+            OverloadState::Stalled | OverloadState::Overcurrent => {
+                self.peak = self.peak.max(current);
+                if self.scale > 0.0 {
+                    self.scale = (self.scale - dt.as_secs_f32() / RAMP_TIME.as_secs_f32()).max(0.0);
+                    return None;
+                }
+                self.off_for += dt;
+                if self.off_for >= self.config.cooldown {
+                    self.release();
+                    return Some(self.state);
+                }
+                None
+            }
+        }
+    }
+
+    ///Speeds with the affected wheels scaled down
+    pub fn limit(&self, speeds: WheelSpeeds) -> WheelSpeeds {
+        let mut limited = speeds;
+        for (speed, affected) in limited.iter_mut().zip(self.affected) {
+            if affected {
+                *speed *= self.scale;
+            }
+        }
+        limited
+    }
+
+    pub fn state(&self) -> OverloadState {
+        self.state
+    }
+
+    ///Wheels the protection is holding back
+    pub fn affected(&self) -> [bool; 4] {
+        self.affected
+    }
+
+    ///Highest current (A) since the protection tripped
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+
+    fn trip(&mut self, state: OverloadState, affected: [bool; 4], current: f32) {
+        self.state = state;
+        //Wheels still ramping back up after the last trip stay held back
+        for (held, new) in self.affected.iter_mut().zip(affected) {
+            *held = new || (*held && self.scale < 1.0);
+        }
+        self.peak = current;
+        self.stall_for = Duration::ZERO;
+        self.off_for = Duration::ZERO;
+    }
+
+    ///Lets the outputs back on. They ramp up from where they are instead of jumping to the commanded speed.
+    fn release(&mut self) {
+        self.state = OverloadState::Normal;
+        self.stall_for = Duration::ZERO;
+        self.off_for = Duration::ZERO;
+    }
+
+    ///Ramps held-back outputs up toward full speed
+    fn recover(&mut self, dt: Duration) {
+        if self.scale < 1.0 {
+            self.scale = (self.scale + dt.as_secs_f32() / RAMP_TIME.as_secs_f32()).min(1.0);
+        }
+        if self.scale >= 1.0 {
+            self.affected = [false; 4];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(20);
+    const FRONT_LEFT: [bool; 4] = [true, false, false, false];
+
+    ///Feeds `current` for `ticks` samples and returns the state changes with the tick they came on
+    fn run(
+        guard: &mut OverloadGuard,
+        current: f32,
+        ticks: u32,
+        start: u32,
+    ) -> Vec<(u32, OverloadState)> {
+        (start..start + ticks)
+            .filter_map(|tick| {
+                guard
+                    .update(current, TICK, true, FRONT_LEFT)
+                    .map(|state| (tick, state))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_spike_is_let_through() {
+        let mut guard = OverloadGuard::new(OverloadConfig::default());
+        assert!(run(&mut guard, 2.0, 20, 0).is_empty());
+        assert!(run(&mut guard, 0.5, 5, 20).is_empty());
+        assert!(run(&mut guard, 2.0, 20, 25).is_empty());
+        assert_eq!(guard.limit([50.0; 4]), [50.0; 4]);
+    }
+
+    #[test]
+    fn sustained_stall_trips_after_stall_time() {
+        let mut guard = OverloadGuard::new(OverloadConfig::default());
+        //500 ms at 20 ms per sample, tripped on the 25th
+        assert_eq!(run(&mut guard, 2.0, 30, 0), [(24, OverloadState::Stalled)]);
+        assert_eq!(guard.affected(), FRONT_LEFT);
+        let limited = guard.limit([50.0; 4]);
+        assert!(limited[0] < 50.0);
+        assert_eq!(limited[1..], [50.0; 3]);
+    }
+
+    ///Trips a stall on the front left wheel and ramps it down to nothing, returns the next tick
+    fn stall_to_zero(guard: &mut OverloadGuard) -> u32 {
+        run(guard, 2.0, 25, 0);
+        let mut tick = 25;
+        while guard.limit([50.0; 4])[0] > 0.0 {
+            run(guard, 2.0, 1, tick);
+            tick += 1;
+        }
+        tick
+    }
+
+    #[test]
+    fn stall_ramps_down_to_nothing_then_releases_after_cooldown() {
+        let mut guard = OverloadGuard::new(OverloadConfig::default());
+        //500 ms ramp, give or take a sample of rounding
+        let tick = stall_to_zero(&mut guard);
+        assert!((50..=51).contains(&tick), "{tick}");
+        assert_eq!(guard.state(), OverloadState::Stalled);
+        //Held off for the 2 s cooldown, even with the current gone
+        let changes = run(&mut guard, 0.0, 120, tick);
+        assert_eq!(changes, [(tick + 99, OverloadState::Normal)]);
+    }
+
+    #[test]
+    fn released_output_ramps_back_up() {
+        let mut guard = OverloadGuard::new(OverloadConfig::default());
+        let tick = stall_to_zero(&mut guard);
+        run(&mut guard, 0.0, 100, tick);
+        assert_eq!(guard.state(), OverloadState::Normal);
+        //Not straight back to the commanded speed
+        let released = guard.limit([50.0; 4])[0];
+        assert!(released < 5.0, "{released}");
+        run(&mut guard, 0.0, 12, tick + 100);
+        let halfway = guard.limit([50.0; 4])[0];
+        assert!(halfway > 15.0 && halfway < 35.0, "{halfway}");
+        run(&mut guard, 0.0, 15, tick + 112);
+        assert_eq!(guard.limit([50.0; 4]), [50.0; 4]);
+        assert_eq!(guard.affected(), [false; 4]);
+    }
+
+    #[test]
+    fn overcurrent_cuts_every_output_at_once() {
+        let mut guard = OverloadGuard::new(OverloadConfig::default());
+        assert_eq!(
+            run(&mut guard, 3.5, 1, 0),
+            [(0, OverloadState::Overcurrent)]
+        );
+        assert_eq!(guard.limit([50.0, -50.0, 50.0, -50.0]), [0.0; 4]);
+        assert_eq!(guard.peak(), 3.5);
+        assert_eq!(run(&mut guard, 0.0, 100, 1), [(100, OverloadState::Normal)]);
+    }
+
+    #[test]
+    fn disabled_never_trips() {
+        let mut guard = OverloadGuard::new(OverloadConfig {
+            enabled: false,
+            ..OverloadConfig::default()
+        });
+        assert!(run(&mut guard, 5.0, 100, 0).is_empty());
+        assert_eq!(guard.limit([50.0; 4]), [50.0; 4]);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::config::Config;
+use json::{object, JsonValue};
+use std::time::Duration;
+
+///Gains for a PID loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+impl PidGains {
+    pub fn is_valid(&self) -> bool {
+        [self.kp, self.ki, self.kd]
+            .iter()
+            .all(|gain| gain.is_finite() && *gain >= 0.0)
+    }
+}
+
+impl Config for PidGains {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "kp" => self.kp,
+            "ki" => self.ki,
+            "kd" => self.kd,
+        }
+    }
+
+    ///Parses `{"kp": 50, "ki": 100, "kd": 0}`, missing gains are zero
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let gains = Self {
+            kp: data["kp"].as_f32().unwrap_or(0.0),
+            ki: data["ki"].as_f32().unwrap_or(0.0),
+            kd: data["kd"].as_f32().unwrap_or(0.0),
+        };
+        if gains.is_valid() {
+            Some(gains)
+        } else {
+            None
+        }
+    }
+}
+
+///PID loop. The integral is clamped so the output can never wind up past `limit`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pid {
+    integral: f32,
+    last_error: Option<f32>,
+    ///Largest output in either direction
+    limit: f32,
+}
+
+impl Pid {
+    pub fn new(limit: f32) -> Self {
+        Self {
+            integral: 0.0,
+            last_error: None,
+            limit,
+        }
+    }
+
+    ///Output for `error` after `dt` since the last update
+    pub fn update(&mut self, gains: &PidGains, error: f32, dt: Duration) -> f32 {
+        let dt = dt.as_secs_f32();
+        if dt <= 0.0 {
+            return 0.0;
+        }
+        if gains.ki > 0.0 {
+            let bound = self.limit / gains.ki;
+            self.integral = (self.integral + error * dt).clamp(-bound, bound);
+        } else {
+            self.integral = 0.0;
+        }
+        let derivative = match self.last_error {
+            Some(last) => (error - last) / dt,
+            None => 0.0,
+        };
+        self.last_error = Some(error);
+        let output = gains.kp * error + gains.ki * self.integral + gains.kd * derivative;
+        output.clamp(-self.limit, self.limit)
+    }
+
+    ///Forgets the history, e.g. when the wheel stops or changes direction
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_error = None;
+    }
+}
@@ -0,0 +1,160 @@
+use crate::calibration::Calibration;
+use crate::imu;
+use crate::motordriver::Wheel;
+use crate::speedcontrol::MeasuredSpeeds;
+use crate::vehicle::WheelSpeeds;
+use json::{object, JsonValue};
+use std::time::Duration;
+
+///Position on the floor. x is forward and y to the left of where the pose was last reset.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pose {
+    ///Meters
+    pub x: f32,
+    ///Meters
+    pub y: f32,
+    ///Degrees, positive to the left, wrapped to -180..180
+    pub heading: f32,
+}
+
+impl Pose {
+    pub fn to_json(self) -> JsonValue {
+        object! {
+            "x" => self.x,
+            "y" => self.y,
+            "heading" => self.heading,
+        }
+    }
+
+    ///Parses `{"x": 1.2, "y": -0.4, "heading": 90}`, heading 0 if left out
+    pub fn from_json(data: &JsonValue) -> Option<Self> {
+        let x = data["x"].as_f32()?;
+        let y = data["y"].as_f32()?;
+        let heading = data["heading"].as_f32().unwrap_or(0.0);
+        if x.is_finite() && y.is_finite() && heading.is_finite() {
+            Some(Self {
+                x,
+                y,
+                heading: imu::wrap_degrees(heading),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+///What the last pose update was worked out from
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OdometrySource {
+    ///Wheel speeds measured by the encoders
+    Measured,
+    ///Commanded wheel speeds through the calibration model
+    #[default]
+    Commanded,
+}
+
+impl OdometrySource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OdometrySource::Measured => "measured",
+            OdometrySource::Commanded => "commanded",
+        }
+    }
+}
+
+///Dead-reckoning pose. Speeds come from the encoders when both sides have one, otherwise from the commanded
+///speeds and the calibration model. The heading follows the IMU when there is one, it drifts far less than
+///what the wheels make of a skid-steer turn.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Odometry {
+    pose: Pose,
+    source: OdometrySource,
+    //IMU-kurs vid förra uppdateringen
+    last_yaw: Option<f32>,
+}
+
+impl Odometry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    ///Moves the estimate to `pose`, e.g. where the tracking wall saw the vehicle
+    pub fn set_pose(&mut self, pose: Pose) {
+        self.pose = pose;
+    }
+
+    ///Back to the origin, facing along x
+    pub fn reset(&mut self) {
+        self.set_pose(Pose::default());
+    }
+
+    ///Moves the pose by the wheel speeds over `dt`. `speeds` are the speeds sent to the motors, `yaw` the
+    ///unwrapped IMU heading if there is one.
+    pub fn update(
+        &mut self,
+        speeds: WheelSpeeds,
+        measured: MeasuredSpeeds,
+        calibration: &Calibration,
+        yaw: Option<f32>,
+        dt: Duration,
+    ) {
+        let dt = dt.as_secs_f32();
+        //Linear speed (m/s) and rotation rate (degrees/s)
+        let (linear, angular) = match (side_mean(measured, true), side_mean(measured, false)) {
+            (Some(left), Some(right)) => {
+                self.source = OdometrySource::Measured;
+                (
+                    (left + right) / 2.0,
+                    ((right - left) / calibration.track_width).to_degrees(),
+                )
+            }
+            _ => {
+                self.source = OdometrySource::Commanded;
+                let left = side_mean(speeds.map(Some), true).unwrap_or(0.0);
+                let right = side_mean(speeds.map(Some), false).unwrap_or(0.0);
+                let forward = (left + right) / 2.0;
+                let turn = (right - left) / 2.0;
+                (
+                    forward.signum() * calibration.linear_speed(forward.abs()),
+                    turn.signum() * calibration.angular_speed(turn.abs()),
+                )
+            }
+        };
+        let turned = match (yaw, self.last_yaw) {
+            (Some(yaw), Some(last)) => yaw - last,
+            (Some(_), None) => 0.0,
+            (None, _) => angular * dt,
+        };
+        self.last_yaw = yaw;
+        //Moves along the heading halfway through the step
+        let heading = (self.pose.heading + turned / 2.0).to_radians();
+        self.pose.x += linear * dt * heading.cos();
+        self.pose.y += linear * dt * heading.sin();
+        self.pose.heading = imu::wrap_degrees(self.pose.heading + turned);
+    }
+
+    ///State for the telemetry topic
+    pub fn to_json(&self) -> JsonValue {
+        let mut data = self.pose.to_json();
+        data["source"] = self.source.name().into();
+        data
+    }
+}
+
+///Mean of the known values on the left or right side
+fn side_mean(values: [Option<f32>; 4], left: bool) -> Option<f32> {
+    let known: Vec<f32> = Wheel::ALL
+        .iter()
+        .filter(|wheel| wheel.is_left() == left)
+        .filter_map(|wheel| values[wheel.index()])
+        .collect();
+    if known.is_empty() {
+        None
+    } else {
+        Some(known.iter().sum::<f32>() / known.len() as f32)
+    }
+}
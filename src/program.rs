@@ -0,0 +1,618 @@
+use crate::calibration::{self, Calibration};
+use json::{object, JsonValue};
+use std::{fmt, time::Duration};
+
+///Speed (percent) for moves until the program sets its own
+pub const DEFAULT_SPEED: f32 = 75.0;
+///Longest estimated run time that is accepted
+pub const MAX_DURATION: Duration = Duration::from_secs(600);
+
+//Limits for single blocks
+const MAX_METERS: f32 = 10.0;
+const MAX_DEGREES: f32 = 3600.0;
+const MAX_RADIUS: f32 = 5.0;
+const MAX_WAIT: Duration = Duration::from_secs(60);
+const MAX_REPEAT: u32 = 100;
+///Repeats inside repeats
+const MAX_DEPTH: usize = 4;
+///Blocks run in total, with every repeat unrolled
+const MAX_BLOCKS: u32 = 10_000;
+
+///One block from the block builder
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Forward {
+        meters: f32,
+        ///Own speed for this move, otherwise the program speed
+        speed: Option<f32>,
+    },
+    Backward {
+        meters: f32,
+        speed: Option<f32>,
+    },
+    RotateLeft {
+        degrees: f32,
+        speed: Option<f32>,
+    },
+    RotateRight {
+        degrees: f32,
+        speed: Option<f32>,
+    },
+    ///Stands still
+    Wait(Duration),
+    ///Speed in percent for the moves that follow
+    SetSpeed(f32),
+    ///Drives along a circle with `radius` meters for `degrees`. Positive degrees turn left.
+    Arc {
+        radius: f32,
+        degrees: f32,
+        speed: Option<f32>,
+    },
+    ///Runs the inner blocks `times` times
+    Repeat {
+        times: u32,
+        instructions: Vec<Instruction>,
+    },
+    ///Sets the status LED (red, green, blue)
+    Led([u8; 3]),
+    ///Stops the vehicle and ends the program
+    Stop,
+}
+
+///A block builder program addressed to this vehicle
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+}
+
+///Estimated run time and driven distance (m) of a program
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Estimate {
+    pub duration: Duration,
+    pub distance: f32,
+}
+
+impl Estimate {
+    pub fn to_json(self) -> JsonValue {
+        object! {
+            "duration" => self.duration.as_millis() as u64,
+            "distance" => self.distance,
+        }
+    }
+}
+
+///Why a program was rejected. `index` is the path to the bad block, with one entry per nested repeat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramError {
+    pub index: Vec<usize>,
+    pub reason: String,
+}
+
+impl ProgramError {
+    fn new(reason: impl Into<String>) -> Self {
+        Self {
+            index: Vec::new(),
+            reason: reason.into(),
+        }
+    }
+
+    ///Index as shown to the user, e.g. "3.1" for the second block inside the repeat at index 3
+    pub fn index_str(&self) -> String {
+        index_str(&self.index)
+    }
+
+    ///`{"error": .., "index": "3.1"}`, without index when the error is not about a single block
+    pub fn to_json(&self) -> JsonValue {
+        let mut data = object! { "error" => self.reason.as_str() };
+        if !self.index.is_empty() {
+            data["index"] = self.index_str().into();
+        }
+        data
+    }
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.index.is_empty() {
+            write!(f, "{}", self.reason)
+        } else {
+            write!(f, "Instruktion {}: {}", self.index_str(), self.reason)
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+impl Instruction {
+    ///Parses one block, an object with a single key: `{"forward": 1.5}`, `{"backward": 1}`, `{"rotateL": 90}`,
+    ///`{"rotateR": 45.5}`, `{"wait": 500}` (ms), `{"setSpeed": 60}`, `{"arc": {"radius": 0.5, "angle": -90}}`,
+    ///`{"repeat": {"times": 4, "instructions": [..]}}`, `{"led": [255, 0, 0]}` or `{"stop": true}`.
+    ///Moves can also be given with their own speed: `{"forward": {"meters": 1.5, "speed": 40}}`,
+    ///`{"rotateL": {"degrees": 90, "speed": 40}}` and `"speed"` inside `arc`.
+    pub fn from_json(data: &JsonValue) -> Result<Self, ProgramError> {
+        if !data.is_object() || data.len() != 1 {
+            return Err(ProgramError::new("ett block ska ha exakt en nyckel"));
+        }
+        let (key, value) = data.entries().next().unwrap();
+        let instruction = match key {
+            "forward" => {
+                let (meters, speed) = amount(value, "meters")?;
+                Instruction::Forward { meters, speed }
+            }
+            "backward" => {
+                let (meters, speed) = amount(value, "meters")?;
+                Instruction::Backward { meters, speed }
+            }
+            "rotateL" => {
+                let (degrees, speed) = amount(value, "degrees")?;
+                Instruction::RotateLeft { degrees, speed }
+            }
+            "rotateR" => {
+                let (degrees, speed) = amount(value, "degrees")?;
+                Instruction::RotateRight { degrees, speed }
+            }
+            "wait" => {
+                //Checked here rather than in `check`, a Duration cannot hold every finite number
+                let millis = non_negative(value)?;
+                if millis > MAX_WAIT.as_millis() as f32 {
+                    return Err(ProgramError::new(format!(
+                        "högst {} s väntan per block",
+                        MAX_WAIT.as_secs()
+                    )));
+                }
+                Instruction::Wait(Duration::from_secs_f32(millis / 1000.0))
+            }
+            "setSpeed" => Instruction::SetSpeed(speed(value)?),
+            "arc" => {
+                let radius = non_negative(&value["radius"])?;
+                let degrees = finite(&value["angle"])?;
+                if radius == 0.0 {
+                    return Err(ProgramError::new("radien ska vara större än noll"));
+                }
+                Instruction::Arc {
+                    radius,
+                    degrees,
+                    speed: optional_speed(&value["speed"])?,
+                }
+            }
+            "repeat" => match value["times"].as_u32() {
+                Some(times) => Instruction::Repeat {
+                    times,
+                    instructions: parse_list(&value["instructions"])?,
+                },
+                None => return Err(ProgramError::new("times ska vara ett heltal >= 0")),
+            },
+            "led" => {
+                let colour: Vec<u8> = value.members().filter_map(|c| c.as_u8()).collect();
+                match (value.len(), colour.as_slice()) {
+                    (3, &[red, green, blue]) => Instruction::Led([red, green, blue]),
+                    _ => return Err(ProgramError::new("led ska vara [röd, grön, blå] 0 - 255")),
+                }
+            }
+            "stop" => match value.as_bool() {
+                Some(true) => Instruction::Stop,
+                _ => return Err(ProgramError::new("stop ska vara true")),
+            },
+            other => return Err(ProgramError::new(format!("okänd instruktion \"{other}\""))),
+        };
+        Ok(instruction)
+    }
+
+    ///Speed given on the block itself, for moves that have one
+    pub fn speed(&self) -> Option<f32> {
+        match *self {
+            Instruction::Forward { speed, .. }
+            | Instruction::Backward { speed, .. }
+            | Instruction::RotateLeft { speed, .. }
+            | Instruction::RotateRight { speed, .. }
+            | Instruction::Arc { speed, .. } => speed,
+            _ => None,
+        }
+    }
+}
+
+impl Program {
+    ///Parses the `instructions` array of a block builder message and checks it against the limits.
+    ///One bad block rejects the whole program.
+    pub fn from_json(data: &JsonValue) -> Result<Self, ProgramError> {
+        let program = Self {
+            instructions: parse_list(&data["instructions"])?,
+        };
+        let blocks = check_list(&program.instructions, 0)?;
+        if blocks > MAX_BLOCKS {
+            return Err(ProgramError::new(format!(
+                "programmet kör {blocks} block, högst {MAX_BLOCKS} tillåts"
+            )));
+        }
+        Ok(program)
+    }
+
+    ///Run time and distance from the calibration model. `cap` gives the speed a move asking for a speed
+    ///will really get. Fails if the program would run longer than `MAX_DURATION`.
+    pub fn estimate(
+        &self,
+        calibration: &Calibration,
+        cap: impl Fn(f32) -> f32,
+    ) -> Result<Estimate, ProgramError> {
+        let mut estimate = Estimate::default();
+        let mut speed = DEFAULT_SPEED;
+        for instruction in &self.instructions {
+            if !add_estimate(instruction, calibration, &cap, &mut speed, &mut estimate) {
+                break;
+            }
+        }
+        if estimate.duration > MAX_DURATION {
+            return Err(ProgramError::new(format!(
+                "programmet tar ungefär {} s, högst {} s tillåts",
+                estimate.duration.as_secs(),
+                MAX_DURATION.as_secs()
+            )));
+        }
+        Ok(estimate)
+    }
+}
+
+///Adds one block to the estimate, returns false if the program ends there
+fn add_estimate(
+    instruction: &Instruction,
+    calibration: &Calibration,
+    cap: &impl Fn(f32) -> f32,
+    speed: &mut f32,
+    estimate: &mut Estimate,
+) -> bool {
+    let actual = cap(instruction.speed().unwrap_or(*speed));
+    match *instruction {
+        Instruction::Forward { meters, .. } | Instruction::Backward { meters, .. } => {
+            estimate.duration += calibration.drive_time(meters, actual);
+            estimate.distance += meters;
+        }
+        Instruction::RotateLeft { degrees, .. } | Instruction::RotateRight { degrees, .. } => {
+            estimate.duration += calibration.rotate_time(degrees, actual);
+        }
+        Instruction::Arc {
+            radius, degrees, ..
+        } => {
+            estimate.duration += calibration.arc_time(radius, degrees, actual);
+            estimate.distance += calibration::arc_length(radius, degrees);
+        }
+        Instruction::Wait(duration) => estimate.duration += duration,
+        Instruction::SetSpeed(new_speed) => *speed = new_speed,
+        Instruction::Repeat {
+            times,
+            ref instructions,
+        } => {
+            for _ in 0..times {
+                for inner in instructions {
+                    if !add_estimate(inner, calibration, cap, speed, estimate) {
+                        return false;
+                    }
+                }
+            }
+        }
+        Instruction::Led(_) => {}
+        Instruction::Stop => return false,
+    }
+    true
+}
+
+///Checks the ranges of every block, returns how many blocks will run with the repeats unrolled
+fn check_list(instructions: &[Instruction], depth: usize) -> Result<u32, ProgramError> {
+    let mut blocks: u32 = 0;
+    for (i, instruction) in instructions.iter().enumerate() {
+        let count = check(instruction, depth).map_err(|mut e| {
+            e.index.insert(0, i);
+            e
+        })?;
+        blocks = blocks.saturating_add(count);
+    }
+    Ok(blocks)
+}
+
+fn check(instruction: &Instruction, depth: usize) -> Result<u32, ProgramError> {
+    let reason = match *instruction {
+        Instruction::Forward { meters, .. } | Instruction::Backward { meters, .. }
+            if meters > MAX_METERS =>
+        {
+            format!("högst {MAX_METERS} m per block")
+        }
+        Instruction::RotateLeft { degrees, .. } | Instruction::RotateRight { degrees, .. }
+            if degrees > MAX_DEGREES =>
+        {
+            format!("högst {MAX_DEGREES} grader per block")
+        }
+        Instruction::Arc { radius, .. } if radius > MAX_RADIUS => {
+            format!("radien får vara högst {MAX_RADIUS} m")
+        }
+        Instruction::Arc { degrees, .. } if degrees.abs() > MAX_DEGREES => {
+            format!("högst {MAX_DEGREES} grader per block")
+        }
+        Instruction::Repeat { times, .. } if times > MAX_REPEAT => {
+            format!("högst {MAX_REPEAT} upprepningar")
+        }
+        Instruction::Repeat { .. } if depth >= MAX_DEPTH => {
+            format!("högst {MAX_DEPTH} upprepningar i varandra")
+        }
+        Instruction::Repeat {
+            times,
+            ref instructions,
+        } => return Ok(check_list(instructions, depth + 1)?.saturating_mul(times)),
+        _ => return Ok(1),
+    };
+    Err(ProgramError::new(reason))
+}
+
+///Path to a block as shown to the user, the index in each list joined with dots
+pub fn index_str(path: &[usize]) -> String {
+    path.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+///Parses a list of blocks, prefixing errors with the index of the block they came from
+fn parse_list(data: &JsonValue) -> Result<Vec<Instruction>, ProgramError> {
+    if !data.is_array() {
+        return Err(ProgramError::new("instructions ska vara en lista"));
+    }
+    data.members()
+        .enumerate()
+        .map(|(i, block)| {
+            Instruction::from_json(block).map_err(|mut e| {
+                e.index.insert(0, i);
+                e
+            })
+        })
+        .collect()
+}
+
+fn finite(value: &JsonValue) -> Result<f32, ProgramError> {
+    match value.as_f32() {
+        Some(v) if v.is_finite() => Ok(v),
+        _ => Err(ProgramError::new("ogiltigt tal")),
+    }
+}
+
+fn non_negative(value: &JsonValue) -> Result<f32, ProgramError> {
+    match finite(value)? {
+        v if v >= 0.0 => Ok(v),
+        _ => Err(ProgramError::new("värdet får inte vara negativt")),
+    }
+}
+
+///A distance or angle, either as a plain number or as `{key: .., "speed": ..}`
+fn amount(value: &JsonValue, key: &str) -> Result<(f32, Option<f32>), ProgramError> {
+    if value.is_object() {
+        Ok((non_negative(&value[key])?, optional_speed(&value["speed"])?))
+    } else {
+        Ok((non_negative(value)?, None))
+    }
+}
+
+fn speed(value: &JsonValue) -> Result<f32, ProgramError> {
+    match value.as_f32() {
+        Some(speed) if (1.0..=100.0).contains(&speed) => Ok(speed),
+        _ => Err(ProgramError::new("hastigheten ska vara 1 - 100")),
+    }
+}
+
+fn optional_speed(value: &JsonValue) -> Result<Option<f32>, ProgramError> {
+    if value.is_null() {
+        Ok(None)
+    } else {
+        speed(value).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::CalibrationPoint;
+
+    fn parse(text: &str) -> Result<Program, ProgramError> {
+        Program::from_json(&json::parse(text).unwrap())
+    }
+
+    ///Error and the index it points at
+    fn rejected(text: &str) -> (String, String) {
+        let e = parse(text).unwrap_err();
+        (e.reason.clone(), e.index_str())
+    }
+
+    ///0,5 m/s and 90 degrees/s at speed 50, twice that at 100
+    fn calibration() -> Calibration {
+        Calibration {
+            points: vec![CalibrationPoint {
+                speed: 50.0,
+                linear: 0.5,
+                angular: 90.0,
+            }],
+            track_width: 0.15,
+        }
+    }
+
+    #[test]
+    fn parses_every_block() {
+        let program = parse(
+            r#"{"instructions": [
+                {"forward": 1.5}, {"backward": {"meters": 1, "speed": 40}}, {"rotateL": 90},
+                {"rotateR": {"degrees": 45.5, "speed": 100}}, {"wait": 500}, {"setSpeed": 60},
+                {"arc": {"radius": 0.5, "angle": -90}}, {"repeat": {"times": 2, "instructions": [{"led": [255, 0, 0]}]}},
+                {"stop": true}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            program.instructions,
+            [
+                Instruction::Forward {
+                    meters: 1.5,
+                    speed: None
+                },
+                Instruction::Backward {
+                    meters: 1.0,
+                    speed: Some(40.0)
+                },
+                Instruction::RotateLeft {
+                    degrees: 90.0,
+                    speed: None
+                },
+                Instruction::RotateRight {
+                    degrees: 45.5,
+                    speed: Some(100.0)
+                },
+                Instruction::Wait(Duration::from_millis(500)),
+                Instruction::SetSpeed(60.0),
+                Instruction::Arc {
+                    radius: 0.5,
+                    degrees: -90.0,
+                    speed: None
+                },
+                Instruction::Repeat {
+                    times: 2,
+                    instructions: vec![Instruction::Led([255, 0, 0])]
+                },
+                Instruction::Stop,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_negative_values() {
+        for block in [
+            r#"{"forward": -1}"#,
+            r#"{"rotateL": {"degrees": -90}}"#,
+            r#"{"wait": -5}"#,
+            r#"{"arc": {"radius": -0.5, "angle": 90}}"#,
+        ] {
+            let (reason, index) = rejected(&format!(
+                r#"{{"instructions": [{{"stop": true}}, {block}]}}"#
+            ));
+            assert_eq!(reason, "värdet får inte vara negativt", "{block}");
+            assert_eq!(index, "1");
+        }
+        assert!(
+            parse(r#"{"instructions": [{"repeat": {"times": -1, "instructions": []}}]}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_values_over_the_limits() {
+        for block in [
+            r#"{"forward": 10.5}"#,
+            r#"{"rotateR": 3601}"#,
+            r#"{"arc": {"radius": 6, "angle": 90}}"#,
+            r#"{"arc": {"radius": 1, "angle": -3601}}"#,
+            r#"{"wait": 60001}"#,
+            r#"{"wait": 1e30}"#,
+            r#"{"repeat": {"times": 101, "instructions": []}}"#,
+        ] {
+            let text = format!(r#"{{"instructions": [{block}]}}"#);
+            assert_eq!(rejected(&text).1, "0", "{block}");
+        }
+        assert!(parse(r#"{"instructions": [{"forward": 10}, {"wait": 60000}]}"#).is_ok());
+    }
+
+    #[test]
+    fn speed_is_1_to_100() {
+        for speed in ["1", "55.5", "100"] {
+            let text = format!(r#"{{"instructions": [{{"setSpeed": {speed}}}]}}"#);
+            assert!(parse(&text).is_ok(), "{speed}");
+        }
+        for speed in ["0", "0.5", "100.5", "-10", "\"fast\""] {
+            let text = format!(
+                r#"{{"instructions": [{{"forward": {{"meters": 1, "speed": {speed}}}}}]}}"#
+            );
+            assert_eq!(rejected(&text).0, "hastigheten ska vara 1 - 100", "{speed}");
+        }
+    }
+
+    #[test]
+    fn stop_only_takes_true() {
+        assert!(parse(r#"{"instructions": [{"stop": true}]}"#).is_ok());
+        for value in ["false", "1", "\"yes\"", "null"] {
+            let text = format!(r#"{{"instructions": [{{"stop": {value}}}]}}"#);
+            assert_eq!(rejected(&text).0, "stop ska vara true", "{value}");
+        }
+    }
+
+    #[test]
+    fn errors_point_into_nested_repeats() {
+        let (_, index) = rejected(
+            r#"{"instructions": [{"forward": 1}, {"repeat": {"times": 2, "instructions": [
+                {"wait": 10}, {"repeat": {"times": 3, "instructions": [{"led": [1, 2, 3]}, {"fly": 1}]}}
+            ]}}]}"#,
+        );
+        assert_eq!(index, "1.1.1");
+        let (_, index) = rejected(
+            r#"{"instructions": [{"repeat": {"times": 2, "instructions": [{"rotateL": 4000}]}}]}"#,
+        );
+        assert_eq!(index, "0.0");
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let mut block = r#"{"stop": true}"#.to_owned();
+        for _ in 0..MAX_DEPTH {
+            block = format!(r#"{{"repeat": {{"times": 1, "instructions": [{block}]}}}}"#);
+        }
+        assert!(parse(&format!(r#"{{"instructions": [{block}]}}"#)).is_ok());
+        block = format!(r#"{{"repeat": {{"times": 1, "instructions": [{block}]}}}}"#);
+        let (_, index) = rejected(&format!(r#"{{"instructions": [{block}]}}"#));
+        assert_eq!(index, "0.0.0.0.0");
+    }
+
+    #[test]
+    fn counts_unrolled_blocks_against_max_blocks() {
+        //100 * 100 = 10 000 blocks is just allowed
+        let text = |blocks: &str| {
+            format!(
+                r#"{{"instructions": [{{"repeat": {{"times": 100, "instructions": [
+                    {{"repeat": {{"times": 100, "instructions": [{blocks}]}}}}
+                ]}}}}]}}"#
+            )
+        };
+        assert!(parse(&text(r#"{"wait": 0}"#)).is_ok());
+        let (reason, index) = rejected(&text(r#"{"wait": 0}, {"wait": 0}"#));
+        assert_eq!(reason, "programmet kör 20000 block, högst 10000 tillåts");
+        assert_eq!(index, "");
+    }
+
+    #[test]
+    fn estimates_time_and_distance() {
+        let program = parse(
+            r#"{"instructions": [
+                {"setSpeed": 50}, {"forward": 1}, {"rotateL": 90}, {"wait": 500},
+                {"repeat": {"times": 2, "instructions": [{"backward": {"meters": 1, "speed": 100}}]}},
+                {"stop": true}, {"forward": 5}
+            ]}"#,
+        )
+        .unwrap();
+        let estimate = program.estimate(&calibration(), |speed| speed).unwrap();
+        //2 s + 1 s + 0,5 s + 2 * 1 s, nothing after the stop
+        assert!(
+            (estimate.duration.as_secs_f32() - 5.5).abs() < 0.01,
+            "{:?}",
+            estimate.duration
+        );
+        assert_eq!(estimate.distance, 3.0);
+        //Capped to speed 50, the backward moves take twice as long
+        let capped = program
+            .estimate(&calibration(), |speed| speed.min(50.0))
+            .unwrap();
+        assert!(
+            (capped.duration.as_secs_f32() - 7.5).abs() < 0.01,
+            "{:?}",
+            capped.duration
+        );
+    }
+
+    #[test]
+    fn estimate_turns_down_long_programs() {
+        //100 * 10 m at 1 m/s
+        let program = parse(
+            r#"{"instructions": [{"repeat": {"times": 100, "instructions": [{"forward": {"meters": 10, "speed": 100}}]}}]}"#,
+        )
+        .unwrap();
+        assert!(program.estimate(&calibration(), |speed| speed).is_err());
+    }
+}
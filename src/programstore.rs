@@ -0,0 +1,174 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+use json::{object, JsonValue};
+use log::debug;
+use std::fmt;
+
+///NVS namespace for stored programs, kept apart from the settings
+const NAMESPACE: &str = "program";
+///Key of the list of stored programs
+const INDEX_KEY: &str = "index";
+///Programs that can be stored at once, each gets its own key p0..p7
+pub const MAX_PROGRAMS: usize = 8;
+///Largest program (JSON) that can be stored (NVS limit for strings)
+pub const MAX_PROGRAM_LEN: usize = 4000;
+pub const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Nvs(EspError),
+    NotFound,
+    ///The program is too large, with its size in bytes
+    TooLarge(usize),
+    Full,
+    InvalidName,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Nvs(e) => write!(f, "NVS-fel: {}", e),
+            StoreError::NotFound => write!(f, "Programmet finns inte"),
+            StoreError::TooLarge(size) => write!(
+                f,
+                "Programmet är {} byte, högst {} byte kan sparas",
+                size, MAX_PROGRAM_LEN
+            ),
+            StoreError::Full => write!(f, "Högst {} program kan sparas", MAX_PROGRAMS),
+            StoreError::InvalidName => write!(f, "Namnet ska vara 1 - {} tecken", MAX_NAME_LEN),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<EspError> for StoreError {
+    fn from(e: EspError) -> Self {
+        StoreError::Nvs(e)
+    }
+}
+
+///A program in the store
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredProgram {
+    pub name: String,
+    ///Size of the stored JSON in bytes
+    pub size: usize,
+    //NVS key holding the program
+    key: String,
+}
+
+///Named block builder programs kept in NVS, so they can be run by name or at startup
+pub struct ProgramStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl ProgramStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    ///Every stored program
+    pub fn list(&self) -> Vec<StoredProgram> {
+        let mut buf = vec![0u8; MAX_PROGRAM_LEN];
+        let index = match self.nvs.get_str(INDEX_KEY, &mut buf) {
+            Ok(Some(data)) => json::parse(data).unwrap_or(JsonValue::Null),
+            Ok(None) => JsonValue::Null,
+            Err(e) => {
+                debug!("Kunde ej läsa programlistan: {}", e);
+                JsonValue::Null
+            }
+        };
+        index
+            .members()
+            .filter_map(|entry| {
+                Some(StoredProgram {
+                    name: entry["name"].as_str()?.to_owned(),
+                    size: entry["size"].as_usize()?,
+                    key: entry["key"].as_str()?.to_owned(),
+                })
+            })
+            .collect()
+    }
+
+    ///The list as `[{"name": .., "size": ..}, ..]`
+    pub fn list_json(&self) -> JsonValue {
+        let list: Vec<JsonValue> = self
+            .list()
+            .iter()
+            .map(|program| object! { "name" => program.name.as_str(), "size" => program.size })
+            .collect();
+        JsonValue::Array(list)
+    }
+
+    ///Reads the program stored as `name`
+    pub fn load(&self, name: &str) -> Result<JsonValue, StoreError> {
+        let program = self.find(name).ok_or(StoreError::NotFound)?;
+        let mut buf = vec![0u8; MAX_PROGRAM_LEN + 1];
+        match self.nvs.get_str(&program.key, &mut buf)? {
+            Some(data) => json::parse(data).map_err(|_| StoreError::NotFound),
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    ///Stores `program` as `name`, replacing a program with the same name
+    pub fn save(&mut self, name: &str, program: &JsonValue) -> Result<(), StoreError> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(StoreError::InvalidName);
+        }
+        let data = program.dump();
+        if data.len() > MAX_PROGRAM_LEN {
+            return Err(StoreError::TooLarge(data.len()));
+        }
+        let mut list = self.list();
+        let key = match list.iter().position(|p| p.name == name) {
+            Some(i) => list.remove(i).key,
+            None if list.len() >= MAX_PROGRAMS => return Err(StoreError::Full),
+            None => (0..MAX_PROGRAMS)
+                .map(|slot| format!("p{slot}"))
+                .find(|key| list.iter().all(|p| &p.key != key))
+                .ok_or(StoreError::Full)?,
+        };
+        self.nvs.set_str(&key, &data)?;
+        list.push(StoredProgram {
+            name: name.to_owned(),
+            size: data.len(),
+            key,
+        });
+        self.store_index(&list)
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), StoreError> {
+        let mut list = self.list();
+        let i = list
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or(StoreError::NotFound)?;
+        let program = list.remove(i);
+        self.store_index(&list)?;
+        self.nvs.remove(&program.key)?;
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<StoredProgram> {
+        self.list().into_iter().find(|p| p.name == name)
+    }
+
+    fn store_index(&mut self, list: &[StoredProgram]) -> Result<(), StoreError> {
+        let index: Vec<JsonValue> = list
+            .iter()
+            .map(|program| {
+                object! {
+                    "name" => program.name.as_str(),
+                    "size" => program.size,
+                    "key" => program.key.as_str(),
+                }
+            })
+            .collect();
+        self.nvs
+            .set_str(INDEX_KEY, &JsonValue::Array(index).dump())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use crate::config::Config;
+use crate::vehicle::WheelSpeeds;
+use json::{object, JsonValue};
+use std::time::Duration;
+
+///Limits for how fast wheel speeds may change
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RampConfig {
+    ///Percent per second when a wheel speeds up
+    pub acceleration: f32,
+    ///Percent per second when a wheel slows down
+    pub deceleration: f32,
+    ///Time a wheel stands still before it changes direction
+    pub reversal_dwell: Duration,
+}
+
+impl Default for RampConfig {
+    fn default() -> Self {
+        Self {
+            acceleration: 200.0,
+            deceleration: 300.0,
+            reversal_dwell: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RampConfig {
+    pub fn is_valid(&self) -> bool {
+        self.acceleration > 0.0 && self.deceleration > 0.0
+    }
+}
+
+impl Config for RampConfig {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "acceleration" => self.acceleration,
+            "deceleration" => self.deceleration,
+            "reversalDwell" => self.reversal_dwell.as_millis() as u64,
+        }
+    }
+
+    ///Parses `{"acceleration": 200, "deceleration": 300, "reversalDwell": 100}` (percent/s and ms)
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            acceleration: data["acceleration"].as_f32()?,
+            deceleration: data["deceleration"].as_f32()?,
+            reversal_dwell: Duration::from_millis(data["reversalDwell"].as_u64()?),
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
+
+///Ramp generator. Moves the actual wheel speeds toward the commanded ones within the acceleration,
+///deceleration and reversal dwell limits. A reversal always slows down to zero and waits the dwell first.
+pub struct Ramp {
+    config: RampConfig,
+    current: WheelSpeeds,
+    //Dwell left per wheel before it may start in the new direction (s)
+    dwell: [f32; 4],
+}
+
+impl Ramp {
+    pub fn new(config: RampConfig) -> Self {
+        Self {
+            config,
+            current: [0.0; 4],
+            dwell: [0.0; 4],
+        }
+    }
+
+    pub fn set_config(&mut self, config: RampConfig) {
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> RampConfig {
+        self.config
+    }
+
+    ///Actual wheel speeds after the last step
+    pub fn current(&self) -> WheelSpeeds {
+        self.current
+    }
+
+    ///True when every wheel has reached its target
+    pub fn is_settled(&self, target: WheelSpeeds) -> bool {
+        self.current == target
+    }
+
+    ///Drops all wheels to zero at once. Used by the emergency stop.
+    pub fn reset(&mut self) {
+        self.current = [0.0; 4];
+        self.dwell = [0.0; 4];
+    }
+
+    ///Advances the ramp by `dt` and returns the new wheel speeds
+    pub fn step(&mut self, target: WheelSpeeds, dt: Duration) -> WheelSpeeds {
+        let dt = dt.as_secs_f32();
+        for (i, target) in target.into_iter().enumerate() {
+            self.current[i] = self.step_wheel(i, target, dt);
+        }
+        self.current
+    }
+
+    fn step_wheel(&mut self, i: usize, target: f32, dt: f32) -> f32 {
+        let current = self.current[i];
+        let accel = self.config.acceleration * dt;
+        let decel = self.config.deceleration * dt;
+
+        if current == 0.0 {
+            if target == 0.0 {
+                return 0.0;
+            }
+            if self.dwell[i] > 0.0 {
+                self.dwell[i] -= dt;
+                return 0.0;
+            }
+            return target.signum() * accel.min(target.abs());
+        }
+
+        if target != 0.0 && target.signum() == current.signum() {
+            //Same direction, speed up or slow down toward the target
+            let diff = target.abs() - current.abs();
+            let magnitude = if diff > 0.0 {
+                current.abs() + accel.min(diff)
+            } else {
+                current.abs() - decel.min(-diff)
+            };
+            return current.signum() * magnitude;
+        }
+
+        //Stopping or reversing, slow down to zero first
+        let magnitude = current.abs() - decel.min(current.abs());
+        if magnitude == 0.0 && target != 0.0 {
+            self.dwell[i] = self.config.reversal_dwell.as_secs_f32();
+        }
+        current.signum() * magnitude
+    }
+}
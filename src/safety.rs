@@ -0,0 +1,206 @@
+use crate::config::Config;
+use crate::vehicle::WheelSpeeds;
+use json::{object, JsonValue};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+///A sensor that has given readings is taken as lost when it has been quiet this long
+const SENSOR_TIMEOUT: Duration = Duration::from_millis(300);
+
+///One measurement from a distance sensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Range {
+    ///Nothing within reach of the sensor
+    Clear,
+    ///Distance (m) to the nearest object ahead
+    Distance(f32),
+}
+
+///Interface for forward-looking distance sensors (VL53L0X...). The control loop reads it every tick and hands
+///new measurements to the vehicle controller.
+pub trait RangeSensor {
+    type Error: fmt::Display;
+
+    ///The latest measurement, None when there is no new one since the last call
+    fn read(&mut self) -> Result<Option<Range>, Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SafetyConfig {
+    ///Limits forward speed near obstacles when a distance sensor is fitted
+    pub enabled: bool,
+    ///No forward speed at all this close (m)
+    pub stop_distance: f32,
+    ///Forward speed is scaled down from this distance (m) to the stop distance
+    pub slow_distance: f32,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            stop_distance: 0.15,
+            slow_distance: 0.5,
+        }
+    }
+}
+
+impl SafetyConfig {
+    pub fn is_valid(&self) -> bool {
+        self.stop_distance >= 0.0 && self.slow_distance >= self.stop_distance
+    }
+}
+
+impl Config for SafetyConfig {
+    fn to_json(&self) -> JsonValue {
+        object! {
+            "enabled" => self.enabled,
+            "stopDistance" => self.stop_distance,
+            "slowDistance" => self.slow_distance,
+        }
+    }
+
+    ///Parses `{"enabled": true, "stopDistance": 0.15, "slowDistance": 0.5}`
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            enabled: data["enabled"].as_bool()?,
+            stop_distance: data["stopDistance"].as_f32()?,
+            slow_distance: data["slowDistance"].as_f32()?,
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
+
+///How the safety bubble limits forward speed right now
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SafetyState {
+    ///Off, no sensor or nothing near, full speed
+    Free,
+    ///Obstacle between the slow and stop distance
+    Slowing,
+    ///Obstacle within the stop distance
+    Blocked,
+    ///The sensor stopped answering, treated as blocked
+    SensorLost,
+}
+
+impl SafetyState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SafetyState::Free => "free",
+            SafetyState::Slowing => "slowing",
+            SafetyState::Blocked => "blocked",
+            SafetyState::SensorLost => "sensorLost",
+        }
+    }
+}
+
+///Safety layer between the commands and the motors. Whatever drives the vehicle (live control, programs,
+///calibration), forward speed is scaled down near an obstacle and stopped within the stop distance.
+///Rotating in place and reversing stay allowed so the vehicle can get away.
+pub struct SafetyBubble {
+    config: SafetyConfig,
+    range: Option<Range>,
+    last_reading: Option<Instant>,
+    state: SafetyState,
+    //Framåtfart har stoppats helt sedan denna gång
+    blocking: bool,
+    //Antal gånger framåtfart har stoppats
+    interventions: u32,
+}
+
+impl SafetyBubble {
+    pub fn new(config: SafetyConfig) -> Self {
+        Self {
+            config,
+            range: None,
+            last_reading: None,
+            state: SafetyState::Free,
+            blocking: false,
+            interventions: 0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: SafetyConfig) {
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> SafetyConfig {
+        self.config
+    }
+
+    ///Adds a new measurement from the sensor
+    pub fn update(&mut self, range: Range) {
+        self.range = Some(range);
+        self.last_reading = Some(Instant::now());
+    }
+
+    ///Distance (m) to the nearest object ahead, None when clear or without a sensor
+    pub fn distance(&self) -> Option<f32> {
+        match self.range {
+            Some(Range::Distance(distance)) => Some(distance),
+            _ => None,
+        }
+    }
+
+    ///Share (0..1) of forward speed allowed right now. Updates the state, called once per tick.
+    pub fn allowed(&mut self) -> f32 {
+        let (state, allowed) = match self.last_reading {
+            Some(_) if !self.config.enabled => (SafetyState::Free, 1.0),
+            None => (SafetyState::Free, 1.0),
+            Some(last) if last.elapsed() > SENSOR_TIMEOUT => (SafetyState::SensorLost, 0.0),
+            Some(_) => match self.range {
+                Some(Range::Distance(d)) if d <= self.config.stop_distance => {
+                    (SafetyState::Blocked, 0.0)
+                }
+                Some(Range::Distance(d)) if d < self.config.slow_distance => (
+                    SafetyState::Slowing,
+                    (d - self.config.stop_distance)
+                        / (self.config.slow_distance - self.config.stop_distance),
+                ),
+                _ => (SafetyState::Free, 1.0),
+            },
+        };
+        self.state = state;
+        allowed
+    }
+
+    ///Scales down `speeds` when they take the vehicle forward. All wheels are scaled together so a turn keeps
+    ///its radius, and a rotation in place is left alone.
+    pub fn limit(&self, speeds: WheelSpeeds, allowed: f32) -> WheelSpeeds {
+        let forward = speeds.iter().sum::<f32>() / speeds.len() as f32;
+        if forward > 0.0 && allowed < 1.0 {
+            speeds.map(|s| s * allowed)
+        } else {
+            speeds
+        }
+    }
+
+    ///Records whether a forward command is being stopped completely. True when that just started.
+    pub fn record_blocking(&mut self, blocking: bool) -> bool {
+        let started = blocking && !self.blocking;
+        if started {
+            self.interventions += 1;
+        }
+        self.blocking = blocking;
+        started
+    }
+
+    pub fn state(&self) -> SafetyState {
+        self.state
+    }
+
+    ///State for the telemetry topic
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            "distance" => self.distance(),
+            "state" => self.state.name(),
+            "blocking" => self.blocking,
+            "interventions" => self.interventions,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::battery::BatteryConfig;
+use crate::calibration::Calibration;
+use crate::collision::CollisionConfig;
+use crate::config::Config;
+use crate::controllerhal;
+use crate::dutycurve::DutyCurves;
+use crate::imu::HeadingConfig;
+use crate::joystick::JoystickConfig;
+use crate::motordriver::WheelMap;
+use crate::overload::OverloadConfig;
+use crate::ramp::RampConfig;
+use crate::safety::SafetyConfig;
+use crate::speedcontrol::SpeedControlConfig;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+use json::JsonValue;
+use log::debug;
+use std::time::Duration;
+
+///NVS namespace for all vehicle settings
+const NAMESPACE: &str = "fordon";
+///Largest JSON document stored under one key (NVS limit for strings)
+const MAX_VALUE_LEN: usize = 4000;
+
+//NVS keys can be at most 15 characters
+const WHEEL_MAP_KEY: &str = "wheelmap";
+const DUTY_CURVES_KEY: &str = "dutycurves";
+const RAMP_KEY: &str = "ramp";
+const JOYSTICK_KEY: &str = "joystick";
+const DEADMAN_KEY: &str = "deadman";
+const AUTORUN_KEY: &str = "autorun";
+const CALIBRATION_KEY: &str = "calibration";
+const SPEED_CONTROL_KEY: &str = "speedcontrol";
+const HEADING_KEY: &str = "heading";
+const SAFETY_KEY: &str = "safety";
+const COLLISION_KEY: &str = "collision";
+const BATTERY_KEY: &str = "battery";
+const OVERLOAD_KEY: &str = "overload";
+
+///Deadman window used until one has been configured. Off, so clients that send a command once keep working
+///until the deadman is turned on with /user/deadman.
+const DEFAULT_DEADMAN_TIMEOUT: Duration = Duration::ZERO;
+///Shortest wait before a program is run at startup, so there is time to put the vehicle down or stop it
+pub const MIN_AUTORUN_DELAY: Duration = Duration::from_secs(5);
+
+///Vehicle settings stored as JSON strings in the NVS partition so they survive a reflash of the firmware.
+pub struct Settings {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Settings {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    ///Reads and parses the JSON stored under `key`. Missing or broken values give None.
+    fn load_json(&self, key: &str) -> Option<JsonValue> {
+        let mut buf = vec![0u8; MAX_VALUE_LEN];
+        match self.nvs.get_str(key, &mut buf) {
+            Ok(Some(data)) => match json::parse(data) {
+                Ok(jsondata) => Some(jsondata),
+                Err(_) => {
+                    debug!("Kunde ej tolka inställning {}", key);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                debug!("Kunde ej läsa inställning {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn store_json(&mut self, key: &str, value: &JsonValue) -> Result<(), EspError> {
+        self.nvs.set_str(key, &value.dump())
+    }
+
+    ///Stored config, or its default if none has been saved or it no longer parses
+    pub fn config<C: Stored>(&self) -> C {
+        self.load_json(C::KEY)
+            .and_then(|data| C::from_json(&data))
+            .unwrap_or_default()
+    }
+
+    pub fn set_config<C: Stored>(&mut self, config: &C) -> Result<(), EspError> {
+        self.store_json(C::KEY, &config.to_json())
+    }
+
+    ///Stored wheel map, or the SCB Motordrive3 wiring if none has been saved or it does not fit the driver
+    pub fn wheel_map(&self) -> WheelMap {
+        Some(self.config::<WheelMap>())
+            .filter(|map| map.is_valid(controllerhal::CHANNELS))
+            .unwrap_or_default()
+    }
+
+    ///Deadman window for live control, zero means turned off
+    pub fn deadman_timeout(&self) -> Duration {
+        self.load_json(DEADMAN_KEY)
+            .and_then(|data| data["timeout"].as_u64())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_DEADMAN_TIMEOUT)
+    }
+
+    pub fn set_deadman_timeout(&mut self, timeout: Duration) -> Result<(), EspError> {
+        let data = json::object! { "timeout" => timeout.as_millis() as u64 };
+        self.store_json(DEADMAN_KEY, &data)
+    }
+
+    ///Stored program to run at startup and how long to wait before it starts
+    pub fn autorun(&self) -> Option<(String, Duration)> {
+        let data = self.load_json(AUTORUN_KEY)?;
+        let name = data["name"].as_str()?.to_owned();
+        let delay = Duration::from_millis(data["delay"].as_u64()?).max(MIN_AUTORUN_DELAY);
+        Some((name, delay))
+    }
+
+    ///Sets or, with None, clears the program run at startup
+    pub fn set_autorun(&mut self, autorun: Option<(&str, Duration)>) -> Result<(), EspError> {
+        match autorun {
+            Some((name, delay)) => {
+                let delay = delay.max(MIN_AUTORUN_DELAY);
+                let data = json::object! { "name" => name, "delay" => delay.as_millis() as u64 };
+                self.store_json(AUTORUN_KEY, &data)
+            }
+            None => {
+                self.nvs.remove(AUTORUN_KEY)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+///A config kept in NVS under its own key. Loading falls back to `Default` when nothing usable is stored.
+pub trait Stored: Config + Default {
+    const KEY: &'static str;
+    ///What the config is called in log messages
+    const NAME: &'static str;
+}
+
+impl Stored for WheelMap {
+    const KEY: &'static str = WHEEL_MAP_KEY;
+    const NAME: &'static str = "hjulkarta";
+}
+
+impl Stored for DutyCurves {
+    const KEY: &'static str = DUTY_CURVES_KEY;
+    const NAME: &'static str = "dutykurvor";
+}
+
+impl Stored for RampConfig {
+    const KEY: &'static str = RAMP_KEY;
+    const NAME: &'static str = "rampinställningar";
+}
+
+impl Stored for JoystickConfig {
+    const KEY: &'static str = JOYSTICK_KEY;
+    const NAME: &'static str = "joystickinställningar";
+}
+
+impl Stored for Calibration {
+    const KEY: &'static str = CALIBRATION_KEY;
+    const NAME: &'static str = "kalibrering";
+}
+
+impl Stored for SpeedControlConfig {
+    const KEY: &'static str = SPEED_CONTROL_KEY;
+    const NAME: &'static str = "hastighetsreglering";
+}
+
+impl Stored for HeadingConfig {
+    const KEY: &'static str = HEADING_KEY;
+    const NAME: &'static str = "kursreglering";
+}
+
+impl Stored for SafetyConfig {
+    const KEY: &'static str = SAFETY_KEY;
+    const NAME: &'static str = "hinderstopp";
+}
+
+impl Stored for CollisionConfig {
+    const KEY: &'static str = COLLISION_KEY;
+    const NAME: &'static str = "krockdetektering";
+}
+
+impl Stored for BatteryConfig {
+    const KEY: &'static str = BATTERY_KEY;
+    const NAME: &'static str = "batterinivåer";
+}
+
+impl Stored for OverloadConfig {
+    const KEY: &'static str = OVERLOAD_KEY;
+    const NAME: &'static str = "överlastskydd";
+}
@@ -0,0 +1,239 @@
+use crate::config::Config;
+use crate::encoder::WheelPulses;
+use crate::pid::{Pid, PidGains};
+use crate::vehicle::WheelSpeeds;
+use json::{object, JsonValue};
+use std::time::{Duration, Instant};
+
+///Largest correction the PID may add to or take from a wheel (percent)
+const MAX_CORRECTION: f32 = 50.0;
+///Weight of the newest measurement in the filtered wheel speed. A tick only holds a few pulses.
+const FILTER: f32 = 0.3;
+///Measurements further apart than this are too old to compute a speed from
+const MAX_GAP: Duration = Duration::from_millis(500);
+
+///Measured speed per wheel (m/s), indexed by `Wheel::index`. None for wheels without an encoder.
+pub type MeasuredSpeeds = [Option<f32>; 4];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeedControlConfig {
+    ///Off, or no encoders fitted, leaves the wheels open loop
+    pub enabled: bool,
+    ///Encoder pulses per meter of wheel travel
+    pub pulses_per_meter: f32,
+    ///Wheel speed (m/s) that speed 100 stands for
+    pub max_speed: f32,
+    ///Gains in percent per m/s of speed error
+    pub gains: PidGains,
+}
+
+impl Default for SpeedControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pulses_per_meter: 2000.0,
+            max_speed: 0.48,
+            gains: PidGains {
+                kp: 50.0,
+                ki: 100.0,
+                kd: 0.0,
+            },
+        }
+    }
+}
+
+impl SpeedControlConfig {
+    pub fn is_valid(&self) -> bool {
+        self.pulses_per_meter > 0.0 && self.max_speed > 0.0 && self.gains.is_valid()
+    }
+}
+
+impl Config for SpeedControlConfig {
+    fn to_json(&self) -> JsonValue {
+        let mut data = object! {
+            "enabled" => self.enabled,
+            "pulsesPerMeter" => self.pulses_per_meter,
+            "maxSpeed" => self.max_speed,
+        };
+        for (key, value) in self.gains.to_json().entries() {
+            data[key] = value.clone();
+        }
+        data
+    }
+
+    ///Parses `{"enabled": true, "pulsesPerMeter": 2000, "maxSpeed": 0.48, "kp": 50, "ki": 100, "kd": 0}`
+    fn from_json(data: &JsonValue) -> Option<Self> {
+        let config = Self {
+            enabled: data["enabled"].as_bool()?,
+            pulses_per_meter: data["pulsesPerMeter"].as_f32()?,
+            max_speed: data["maxSpeed"].as_f32()?,
+            gains: PidGains::from_json(data)?,
+        };
+        if config.is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+}
+
+///Closed-loop wheel speed. Speed in percent is taken as a share of `max_speed`, and a PID per wheel trims the
+///ramped speed until the measured speed matches. The duty curves stay as feed forward, so the loop only has
+///to make up for the battery level, load and wheel differences.
+pub struct SpeedControl {
+    config: SpeedControlConfig,
+    pids: [Pid; 4],
+    measured: MeasuredSpeeds,
+    last_measure: Option<Instant>,
+}
+
+impl SpeedControl {
+    pub fn new(config: SpeedControlConfig) -> Self {
+        Self {
+            config,
+            pids: [(); 4].map(|_| Pid::new(MAX_CORRECTION)),
+            measured: [None; 4],
+            last_measure: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: SpeedControlConfig) {
+        self.config = config;
+        self.reset();
+    }
+
+    pub fn get_config(&self) -> SpeedControlConfig {
+        self.config
+    }
+
+    ///Updates the measured speeds with the pulses counted since the last call
+    pub fn measure(&mut self, pulses: WheelPulses) {
+        self.measure_at(pulses, Instant::now());
+    }
+
+    ///Like `measure` with the pulses counted up to `now`
+    pub fn measure_at(&mut self, pulses: WheelPulses, now: Instant) {
+        let dt = self.last_measure.map(|last| now.duration_since(last));
+        self.last_measure = Some(now);
+        for (measured, pulses) in self.measured.iter_mut().zip(pulses) {
+            *measured = match (pulses, dt) {
+                (Some(pulses), Some(dt)) if !dt.is_zero() && dt <= MAX_GAP => {
+                    let speed = pulses as f32 / self.config.pulses_per_meter / dt.as_secs_f32();
+                    Some(match *measured {
+                        Some(last) => last + (speed - last) * FILTER,
+                        None => speed,
+                    })
+                }
+                (Some(_), _) => Some(0.0),
+                (None, _) => None,
+            };
+        }
+    }
+
+    pub fn measured(&self) -> MeasuredSpeeds {
+        self.measured
+    }
+
+    ///Ramped speeds with the PID correction added, for the wheels that have an encoder
+    pub fn correct(&mut self, speeds: WheelSpeeds, dt: Duration) -> WheelSpeeds {
+        let mut corrected = speeds;
+        for i in 0..4 {
+            let speed = speeds[i];
+            let measured = match self.measured[i] {
+                Some(measured) if self.config.enabled => measured,
+                _ => continue,
+            };
+            if speed == 0.0 {
+                //Standing still or about to reverse, nothing to hold
+                self.pids[i].reset();
+                continue;
+            }
+            let target = speed / 100.0 * self.config.max_speed;
+            let correction = self.pids[i].update(&self.config.gains, target - measured, dt);
+            //The correction may slow a wheel down to a stop but never turn it the other way
+            corrected[i] = if speed > 0.0 {
+                (speed + correction).clamp(0.0, 100.0)
+            } else {
+                (speed + correction).clamp(-100.0, 0.0)
+            };
+        }
+        corrected
+    }
+
+    ///Forgets the PID history, e.g. after an emergency stop
+    pub fn reset(&mut self) {
+        for pid in &mut self.pids {
+            pid.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::SimulatedEncoder;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    ///Runs the loop for `ticks` steps of simulated time against wheels that only reach `strength` of the
+    ///speed they are asked for, like on a flat battery. Returns the last measured and corrected speeds.
+    fn run(
+        config: SpeedControlConfig,
+        speed: f32,
+        strength: f32,
+        ticks: u32,
+    ) -> (MeasuredSpeeds, f32) {
+        let mut control = SpeedControl::new(config);
+        let mut encoder = SimulatedEncoder::new(config.pulses_per_meter);
+        let mut now = Instant::now();
+        encoder.take_pulses_at(now);
+        let mut corrected = [speed; 4];
+        for _ in 0..ticks {
+            now += TICK;
+            control.measure_at(encoder.take_pulses_at(now), now);
+            corrected = control.correct([speed; 4], TICK);
+            encoder.set_speeds(corrected.map(|s| Some(s / 100.0 * config.max_speed * strength)));
+        }
+        (control.measured(), corrected[0])
+    }
+
+    #[test]
+    fn measures_the_simulated_wheel_speed() {
+        let config = SpeedControlConfig::default();
+        let (measured, corrected) = run(config, 50.0, 1.0, 30);
+        //Off, the speeds go through untouched
+        assert_eq!(corrected, 50.0);
+        for speed in measured {
+            let speed = speed.unwrap();
+            assert!((speed - 0.24).abs() < 0.01, "{speed}");
+        }
+    }
+
+    #[test]
+    fn makes_up_for_weak_wheels() {
+        let config = SpeedControlConfig {
+            enabled: true,
+            ..SpeedControlConfig::default()
+        };
+        //Ten seconds of driving
+        let (measured, corrected) = run(config, 50.0, 0.7, 1000);
+        assert!((corrected - 50.0 / 0.7).abs() < 3.0, "{corrected}");
+        for speed in measured {
+            let speed = speed.unwrap();
+            assert!((speed - 0.24).abs() < 0.01, "{speed}");
+        }
+    }
+
+    #[test]
+    fn wheels_without_encoder_run_open_loop() {
+        let config = SpeedControlConfig {
+            enabled: true,
+            ..SpeedControlConfig::default()
+        };
+        let mut control = SpeedControl::new(config);
+        control.measure([None; 4]);
+        control.measure([None; 4]);
+        assert_eq!(control.measured(), [None; 4]);
+        assert_eq!(control.correct([50.0; 4], TICK), [50.0; 4]);
+    }
+}
@@ -0,0 +1,1129 @@
+use crate::battery::{BatteryConfig, BatteryMonitor, BatteryState};
+use crate::calibration::Calibration;
+use crate::collision::{CollisionConfig, CollisionDetector};
+use crate::dutycurve::DutyCurves;
+use crate::encoder::WheelPulses;
+use crate::estop;
+use crate::imu::{self, HeadingConfig, HeadingEstimator, ImuSample};
+use crate::joystick::JoystickConfig;
+use crate::motordriver::{sides, MotorDriver, Wheel, WheelDuties};
+use crate::overload::{OverloadConfig, OverloadGuard, OverloadState, PowerSample};
+use crate::pose::{Odometry, Pose};
+use crate::ramp::{Ramp, RampConfig};
+use crate::safety::{Range, SafetyBubble, SafetyConfig, SafetyState};
+use crate::speedcontrol::{MeasuredSpeeds, SpeedControl, SpeedControlConfig};
+use json::{object, JsonValue};
+use log::debug;
+use std::time::{Duration, Instant};
+
+///Period of the control loop that runs the ramp generator
+pub const TICK: Duration = Duration::from_millis(20);
+///Longest step the ramp takes in one tick, if the control loop has been held up
+const MAX_TICK: Duration = Duration::from_millis(100);
+///A rotation closed on the heading slows down over the last degrees
+const SLOWDOWN_ANGLE: f32 = 30.0;
+///Slowest speed a rotation closed on the heading slows down to, the wheels have to keep turning
+const MIN_ROTATE_SPEED: f32 = 15.0;
+///Largest steering (percent) when a straight move holds its heading
+const MAX_STEER: f32 = 30.0;
+///A driven wheel the encoders see slower than this (m/s) is taken as stalled
+const STALL_SPEED: f32 = 0.02;
+
+///Signed speed in percent (-100..100) for every wheel, indexed by `Wheel::index`
+pub type WheelSpeeds = [f32; 4];
+
+///What a program move closed on the heading is steering for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeadingGoal {
+    ///Rotate in place until the yaw reaches `target`
+    Rotate { target: f32, speed: f32 },
+    ///Drive straight at signed `speed` and keep the yaw at `heading`
+    Hold { heading: f32, speed: f32 },
+}
+
+///Things the controller did on its own that the operator should hear about
+#[derive(Debug, Clone, PartialEq)]
+pub enum VehicleEvent {
+    ///No live-control refresh arrived within the deadman timeout, the vehicle was stopped
+    Deadman { timeout: Duration },
+    ///The safety bubble stopped a forward command in front of an obstacle, or without its sensor
+    Obstacle {
+        state: SafetyState,
+        distance: Option<f32>,
+    },
+    ///The battery went below the warning level or the cutoff, or was charged again
+    Battery {
+        state: BatteryState,
+        voltage: Option<f32>,
+    },
+    ///The overload protection held back or released the outputs. Holds the peak current (A) and the wheels.
+    Overload {
+        state: OverloadState,
+        current: f32,
+        wheels: [bool; 4],
+    },
+    ///An impact latched the emergency stop. Holds the peak acceleration (g) and how the vehicle was moving.
+    Collision {
+        peak: f32,
+        speed: i32,
+        wheels: WheelSpeeds,
+        measured: MeasuredSpeeds,
+        heading: Option<f32>,
+        rate: f32,
+    },
+}
+
+impl VehicleEvent {
+    ///Event name used on the events topic
+    pub fn name(&self) -> &'static str {
+        match self {
+            VehicleEvent::Deadman { .. } => "deadman",
+            VehicleEvent::Obstacle { .. } => "obstacle",
+            VehicleEvent::Battery { .. } => "battery",
+            VehicleEvent::Overload { .. } => "overload",
+            VehicleEvent::Collision { .. } => "collision",
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            VehicleEvent::Deadman { timeout } => object! {
+                "timeout" => timeout.as_millis() as u64,
+            },
+            VehicleEvent::Obstacle { state, distance } => object! {
+                "state" => state.name(),
+                "distance" => *distance,
+            },
+            VehicleEvent::Battery { state, voltage } => object! {
+                "state" => state.name(),
+                "voltage" => *voltage,
+            },
+            VehicleEvent::Overload {
+                state,
+                current,
+                wheels,
+            } => {
+                let mut names = JsonValue::new_array();
+                for wheel in Wheel::ALL {
+                    if wheels[wheel.index()] {
+                        let _ = names.push(wheel.name());
+                    }
+                }
+                object! {
+                    "state" => state.name(),
+                    "current" => *current,
+                    "wheels" => names,
+                }
+            }
+            VehicleEvent::Collision {
+                peak,
+                speed,
+                wheels,
+                measured,
+                heading,
+                rate,
+            } => {
+                let mut data = object! {
+                    "peak" => *peak,
+                    "speed" => *speed,
+                    "heading" => heading.map(imu::wrap_degrees),
+                    "rate" => *rate,
+                    "wheels" => JsonValue::new_object(),
+                };
+                for wheel in Wheel::ALL {
+                    data["wheels"][wheel.name()] = object! {
+                        "speed" => wheels[wheel.index()],
+                        "measured" => measured[wheel.index()],
+                    };
+                }
+                data
+            }
+        }
+    }
+}
+
+///Vehicle controller. Holds speed and max speed and turns driving commands into wheel duties for any board
+///that implements `MotorDriver`. The emergency stop flag lives in `estop` so it can be set without this lock.
+pub struct Vehicle<M> {
+    driver: M,
+    //Översätter hastighet till duty per hjul och riktning
+    curves: DutyCurves,
+    //Begränsar acceleration och retardation
+    ramp: Ramp,
+    //Deadzone och expo för joystickstyrning
+    joystick: JoystickConfig,
+    //Tidsmodell för programmens tidsstyrda rörelser
+    calibration: Calibration,
+    //Hastighetsreglering per hjul med pulsgivarna
+    speed_control: SpeedControl,
+    //Hjul med pulsgivare
+    encoders: [bool; 4],
+    //Kurs integrerad från gyrot
+    heading: HeadingEstimator,
+    heading_config: HeadingConfig,
+    heading_goal: Option<HeadingGoal>,
+    //Begränsar framåtfart nära hinder
+    safety: SafetyBubble,
+    //Nödstoppar vid krock
+    collision: CollisionDetector,
+    //Batterispänning, begränsar maxhastigheten när den sjunker
+    battery: BatteryMonitor,
+    //Stall- och överströmsskydd på motorströmmen
+    overload: OverloadGuard,
+    power: Option<PowerSample>,
+    last_power: Option<Instant>,
+    //Position uppskattad från hjulen
+    odometry: Odometry,
+    //Sträcka kvar (m) för en rörelse stängd på pulsgivarna
+    distance_left: Option<f32>,
+    //En kurs- eller sträckstyrd rörelse har nått sitt mål
+    move_done: bool,
+    //Beordrad hastighet per hjul, rampen rör sig mot den
+    target: WheelSpeeds,
+    //Senast skrivna duty, så att bussen bara används när något ändras
+    duties: WheelDuties,
+    last_tick: Instant,
+    //Live-styrning måste förnyas inom denna tid, noll stänger av
+    deadman_timeout: Duration,
+    deadman_deadline: Option<Instant>,
+    //Händelser som kontrollslingan ska publicera
+    events: Vec<VehicleEvent>,
+
+    speed: i32,
+    //Gällande maxhastighet, den inställda begränsad av batteriet
+    maxspeed: i32,
+    //Maxhastighet inställd av operatören
+    max_speed_setting: i32,
+    //Utgångarna har kapats för det aktiva nödstoppet
+    outputs_cut: bool,
+}
+
+impl<M: MotorDriver> Vehicle<M> {
+    pub fn new(driver: M, curves: DutyCurves, ramp: RampConfig) -> Self {
+        Self {
+            driver,
+            curves,
+            ramp: Ramp::new(ramp),
+            joystick: JoystickConfig::default(),
+            calibration: Calibration::default(),
+            speed_control: SpeedControl::new(SpeedControlConfig::default()),
+            encoders: [false; 4],
+            heading: HeadingEstimator::new(),
+            heading_config: HeadingConfig::default(),
+            heading_goal: None,
+            safety: SafetyBubble::new(SafetyConfig::default()),
+            collision: CollisionDetector::new(CollisionConfig::default()),
+            battery: BatteryMonitor::new(BatteryConfig::default()),
+            overload: OverloadGuard::new(OverloadConfig::default()),
+            power: None,
+            last_power: None,
+            odometry: Odometry::new(),
+            distance_left: None,
+            move_done: false,
+            target: [0.0; 4],
+            duties: [0; 4],
+            last_tick: Instant::now(),
+            deadman_timeout: Duration::ZERO,
+            deadman_deadline: None,
+            events: Vec::new(),
+            speed: 0,
+            maxspeed: 100,
+            max_speed_setting: 100,
+            outputs_cut: false,
+        }
+    }
+
+    ///Access to the motor driver, e.g. for reading out registers when debugging
+    pub fn driver(&mut self) -> &mut M {
+        &mut self.driver
+    }
+
+    ///Replaces the duty curves. Takes effect from the next driving command
+    pub fn set_duty_curves(&mut self, curves: DutyCurves) {
+        self.curves = curves;
+    }
+
+    pub fn get_duty_curves(&self) -> &DutyCurves {
+        &self.curves
+    }
+
+    pub fn set_ramp_config(&mut self, config: RampConfig) {
+        self.ramp.set_config(config);
+    }
+
+    pub fn get_ramp_config(&self) -> RampConfig {
+        self.ramp.get_config()
+    }
+
+    pub fn set_joystick_config(&mut self, config: JoystickConfig) {
+        self.joystick = config;
+    }
+
+    pub fn get_joystick_config(&self) -> JoystickConfig {
+        self.joystick
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn get_calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    ///Changes the speed control. It can only be turned on when a wheel has an encoder, see `set_encoders`.
+    pub fn set_speed_control_config(
+        &mut self,
+        config: SpeedControlConfig,
+    ) -> Result<(), &'static str> {
+        if config.enabled && !self.encoders.contains(&true) {
+            return Err("Inga pulsgivare, hastighetsregleringen kan inte slås på");
+        }
+        self.speed_control.set_config(config);
+        Ok(())
+    }
+
+    ///Tells which wheels have an encoder. Without any the speed control is turned off.
+    pub fn set_encoders(&mut self, fitted: [bool; 4]) {
+        self.encoders = fitted;
+        let config = self.speed_control.get_config();
+        if config.enabled && !fitted.contains(&true) {
+            debug!("Inga pulsgivare, stänger av hastighetsregleringen");
+            self.speed_control.set_config(SpeedControlConfig {
+                enabled: false,
+                ..config
+            });
+        }
+    }
+
+    pub fn get_speed_control_config(&self) -> SpeedControlConfig {
+        self.speed_control.get_config()
+    }
+
+    pub fn set_heading_config(&mut self, config: HeadingConfig) {
+        self.heading_config = config;
+    }
+
+    pub fn get_heading_config(&self) -> HeadingConfig {
+        self.heading_config
+    }
+
+    pub fn set_safety_config(&mut self, config: SafetyConfig) {
+        self.safety.set_config(config);
+    }
+
+    pub fn get_safety_config(&self) -> SafetyConfig {
+        self.safety.get_config()
+    }
+
+    pub fn set_collision_config(&mut self, config: CollisionConfig) {
+        self.collision.set_config(config);
+    }
+
+    pub fn get_collision_config(&self) -> CollisionConfig {
+        self.collision.get_config()
+    }
+
+    ///Changes the battery levels. A cap from the old levels is lifted until the next sample.
+    pub fn set_battery_config(&mut self, config: BatteryConfig) -> Result<(), M::Error> {
+        self.battery.set_config(config);
+        self.set_max_speed(self.max_speed_setting)
+    }
+
+    pub fn get_battery_config(&self) -> BatteryConfig {
+        self.battery.get_config()
+    }
+
+    pub fn set_overload_config(&mut self, config: OverloadConfig) {
+        self.overload.set_config(config);
+    }
+
+    pub fn get_overload_config(&self) -> OverloadConfig {
+        self.overload.get_config()
+    }
+
+    ///Sets the deadman window for live control. Zero turns the deadman off.
+    pub fn set_deadman_timeout(&mut self, timeout: Duration) {
+        self.deadman_timeout = timeout;
+        self.refresh_deadman();
+    }
+
+    pub fn get_deadman_timeout(&self) -> Duration {
+        self.deadman_timeout
+    }
+
+    ///Events raised since the last call, for the control loop to publish
+    pub fn take_events(&mut self) -> Vec<VehicleEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // --------------- Control loop ---------------
+    ///Moves the wheel duties one step toward the commanded speeds. Called every `TICK` by the control loop.
+    pub fn tick(&mut self) -> Result<(), M::Error> {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last_tick).min(MAX_TICK);
+        self.last_tick = now;
+        if estop::is_active() {
+            //Set from another thread, cut the outputs within this tick
+            if !self.outputs_cut {
+                self.cut_outputs()?;
+            }
+            return Ok(());
+        }
+        self.outputs_cut = false;
+        if let Some(deadline) = self.deadman_deadline {
+            if now >= deadline {
+                debug!(
+                    "Deadman: ingen uppdatering på {:?}, stoppar!",
+                    self.deadman_timeout
+                );
+                self.deadman_deadline = None;
+                self.target = [0.0; 4];
+                self.speed = 0;
+                self.events.push(VehicleEvent::Deadman {
+                    timeout: self.deadman_timeout,
+                });
+            }
+        }
+        self.steer_heading();
+        self.track_distance(dt);
+        if self.battery.state() == BatteryState::Cutoff {
+            self.target = [0.0; 4];
+        }
+        //Limits the ramp goal so it slows down in time, and its output so a stop takes effect at once
+        let allowed = self.safety.allowed();
+        let target = self.safety.limit(self.target, allowed);
+        if self
+            .safety
+            .record_blocking(allowed == 0.0 && target != self.target)
+        {
+            debug!("Hinder framför fordonet, stoppar!");
+            self.events.push(VehicleEvent::Obstacle {
+                state: self.safety.state(),
+                distance: self.safety.distance(),
+            });
+        }
+        let speeds = self.ramp.step(target, dt);
+        let speeds = self.safety.limit(speeds, allowed);
+        let speeds = self.speed_control.correct(speeds, dt);
+        let speeds = self.overload.limit(speeds);
+        self.odometry.update(
+            speeds,
+            self.speed_control.measured(),
+            &self.calibration,
+            self.heading.yaw(),
+            dt,
+        );
+        let duties = self.calculate_duties(speeds);
+        if duties != self.duties {
+            self.driver.set_duties(duties)?;
+            self.duties = duties;
+        }
+        Ok(())
+    }
+
+    ///Starts or renews the deadman window after a live-control command. A stopped vehicle has nothing to expire.
+    fn refresh_deadman(&mut self) {
+        let moving = self.target.iter().any(|s| *s != 0.0);
+        self.deadman_deadline = if moving && !self.deadman_timeout.is_zero() {
+            Some(Instant::now() + self.deadman_timeout)
+        } else {
+            None
+        };
+    }
+
+    ///True when the wheels have reached the commanded speeds
+    pub fn is_settled(&self) -> bool {
+        self.ramp.is_settled(self.target)
+    }
+
+    ///Actual wheel speeds from the ramp generator
+    pub fn get_wheel_speeds(&self) -> WheelSpeeds {
+        self.ramp.current()
+    }
+
+    ///Hands the encoder pulses counted since the last call to the speed control. Called every `TICK` by the
+    ///control loop, before `tick`.
+    pub fn update_encoders(&mut self, pulses: WheelPulses) {
+        self.speed_control.measure(pulses);
+    }
+
+    ///Hands an IMU sample to the heading estimate and the collision detector. Called every `TICK` by the
+    ///control loop, before `tick`. An impact latches the emergency stop.
+    pub fn update_imu(&mut self, sample: ImuSample) -> Result<(), M::Error> {
+        let still = self.target.iter().all(|s| *s == 0.0) && self.is_settled();
+        self.heading
+            .update(&sample, still, self.heading_config.inverted);
+        if let Some(peak) = self.collision.update(&sample) {
+            if !estop::is_active() {
+                debug!("Krock, {} g!", peak);
+                self.events.push(VehicleEvent::Collision {
+                    peak,
+                    speed: self.speed,
+                    wheels: self.ramp.current(),
+                    measured: self.speed_control.measured(),
+                    heading: self.heading.yaw(),
+                    rate: self.heading.rate(),
+                });
+                self.set_emergency_stop(true)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Hands a new distance measurement to the safety bubble. Called by the control loop before `tick` whenever
+    ///the sensor has one.
+    pub fn update_range(&mut self, range: Range) {
+        self.safety.update(range);
+    }
+
+    ///Hands a sample of the battery voltage (V) on the ADC pin to the battery monitor. Called every `TICK` by
+    ///the control loop, before `tick`. A low battery caps the max speed.
+    pub fn update_battery(&mut self, pin_voltage: f32) -> Result<(), M::Error> {
+        let (state, cap) = (self.battery.state(), self.battery.speed_cap());
+        self.battery.update(pin_voltage);
+        if self.battery.state() != state {
+            debug!(
+                "Batteri {}, {:?} V",
+                self.battery.state().name(),
+                self.battery.voltage()
+            );
+            self.events.push(VehicleEvent::Battery {
+                state: self.battery.state(),
+                voltage: self.battery.voltage(),
+            });
+        }
+        if self.battery.speed_cap() != cap {
+            self.set_max_speed(self.max_speed_setting)?;
+        }
+        Ok(())
+    }
+
+    ///Hands a sample from the power monitor to the overload protection. Called every `TICK` by the control
+    ///loop, before `tick`.
+    pub fn update_power(&mut self, sample: PowerSample) {
+        let now = Instant::now();
+        let dt = self
+            .last_power
+            .map_or(Duration::ZERO, |last| now.duration_since(last))
+            .min(MAX_TICK);
+        self.last_power = Some(now);
+        self.power = Some(sample);
+        let speeds = self.ramp.current();
+        let measured = self.speed_control.measured();
+        let driven = speeds.map(|s| s != 0.0);
+        //Driven wheels the encoders see standing still, or every driven wheel when that tells nothing
+        let mut stalled: [bool; 4] = std::array::from_fn(|i| {
+            driven[i] && measured[i].is_some_and(|m| m.abs() < STALL_SPEED)
+        });
+        if !stalled.contains(&true) {
+            stalled = driven;
+        }
+        let state = self
+            .overload
+            .update(sample.current, dt, driven.contains(&true), stalled);
+        if let Some(state) = state {
+            debug!("Motorström {} A, {}", sample.current, state.name());
+            self.events.push(VehicleEvent::Overload {
+                state,
+                current: self.overload.peak(),
+                wheels: self.overload.affected(),
+            });
+        }
+    }
+
+    ///Degrees turned left since start, not wrapped. None without an IMU.
+    pub fn get_heading(&self) -> Option<f32> {
+        self.heading.yaw()
+    }
+
+    ///Position the vehicle thinks it is at
+    pub fn get_pose(&self) -> Pose {
+        self.odometry.pose()
+    }
+
+    pub fn set_pose(&mut self, pose: Pose) {
+        self.odometry.set_pose(pose);
+    }
+
+    ///Puts the pose back at the origin
+    pub fn reset_pose(&mut self) {
+        self.odometry.reset();
+    }
+
+    ///True when a program move closed on the heading or the encoders has reached its goal
+    pub fn is_move_done(&self) -> bool {
+        self.move_done
+    }
+
+    ///Steers toward the heading goal of the running program move
+    fn steer_heading(&mut self) {
+        let (goal, yaw) = match (self.heading_goal, self.heading.yaw()) {
+            (Some(goal), Some(yaw)) => (goal, yaw),
+            _ => return,
+        };
+        let max = self.maxspeed as f32;
+        match goal {
+            HeadingGoal::Rotate { target, speed } => {
+                let remaining = target - yaw;
+                if remaining.abs() <= self.heading_config.tolerance {
+                    debug!("Rotation klar, {} grader från målet", remaining);
+                    self.target = [0.0; 4];
+                    self.heading_goal = None;
+                    self.move_done = true;
+                    return;
+                }
+                //Slows down near the goal and turns back after an overshoot
+                let speed = (speed * remaining.abs() / SLOWDOWN_ANGLE)
+                    .clamp(MIN_ROTATE_SPEED.min(speed), speed)
+                    .min(max);
+                self.target = if remaining > 0.0 {
+                    sides(-speed, speed)
+                } else {
+                    sides(speed, -speed)
+                };
+            }
+            HeadingGoal::Hold { heading, speed } => {
+                let steer = ((heading - yaw) * self.heading_config.kp).clamp(-MAX_STEER, MAX_STEER);
+                self.target = sides(
+                    (speed - steer).clamp(-max, max),
+                    (speed + steer).clamp(-max, max),
+                );
+            }
+        }
+    }
+
+    ///Sets the heading goal for a program move if the heading is known. `from` is the heading when the move
+    ///first started, so a move resumed after a pause keeps its goal.
+    fn close_on_heading(
+        &mut self,
+        from: Option<f32>,
+        goal: impl FnOnce(f32) -> HeadingGoal,
+    ) -> bool {
+        match from {
+            Some(from) if self.heading_config.enabled && self.heading.yaw().is_some() => {
+                self.heading_goal = Some(goal(from));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    ///Counts down the distance of a straight program move with the measured wheel speeds, and stops the
+    ///vehicle when it has been driven
+    fn track_distance(&mut self, dt: Duration) {
+        let left = match self.distance_left {
+            Some(left) => left,
+            None => return,
+        };
+        let known: Vec<f32> = self
+            .speed_control
+            .measured()
+            .iter()
+            .flatten()
+            .map(|speed| speed.abs())
+            .collect();
+        if known.is_empty() {
+            return;
+        }
+        let left = left - known.iter().sum::<f32>() / known.len() as f32 * dt.as_secs_f32();
+        if left <= 0.0 {
+            debug!("Sträckan körd, {} m förbi målet", -left);
+            self.target = [0.0; 4];
+            self.heading_goal = None;
+            self.distance_left = None;
+            self.move_done = true;
+        } else {
+            self.distance_left = Some(left);
+        }
+    }
+
+    ///Sets the distance goal for a straight program move if the encoders measure the wheels
+    fn close_on_distance(&mut self, meters: f32) -> bool {
+        if self.speed_control.measured().iter().any(Option::is_some) {
+            self.distance_left = Some(meters.abs());
+            true
+        } else {
+            false
+        }
+    }
+
+    ///Distance (m) left of a straight program move closed on the encoders
+    pub fn get_distance_left(&self) -> Option<f32> {
+        self.distance_left
+    }
+
+    ///Wheel speeds (m/s) measured by the encoders
+    pub fn get_measured_speeds(&self) -> MeasuredSpeeds {
+        self.speed_control.measured()
+    }
+
+    ///Current state for the telemetry topic
+    pub fn telemetry(&self) -> JsonValue {
+        let speeds = self.ramp.current();
+        let measured = self.speed_control.measured();
+        let mut wheels = JsonValue::new_object();
+        for wheel in Wheel::ALL {
+            wheels[wheel.name()] = object! {
+                "speed" => speeds[wheel.index()],
+                "measured" => measured[wheel.index()],
+            };
+        }
+        object! {
+            "speed" => self.speed,
+            "maxSpeed" => self.maxspeed,
+            "emergencyStop" => estop::is_active(),
+            "heading" => self.heading.yaw().map(imu::wrap_degrees),
+            "safety" => self.safety.to_json(),
+            "battery" => self.battery.to_json(),
+            "pose" => self.odometry.to_json(),
+            "power" => object! {
+                "voltage" => self.power.map(|p| p.voltage),
+                "current" => self.power.map(|p| p.current),
+                "power" => self.power.map(|p| p.power),
+                "state" => self.overload.state().name(),
+            },
+            "wheels" => wheels,
+        }
+    }
+
+    // --------------- Getters & Setters for vehicle---------------
+    ///Sets emergency stop for the controller. Bypasses the ramp and cuts the outputs at once.
+    pub fn set_emergency_stop(&mut self, car_state: bool) -> Result<(), M::Error> {
+        debug!("Emergency stop = {}", car_state);
+        if car_state {
+            estop::trigger();
+            self.cut_outputs()?;
+        } else {
+            estop::release();
+        }
+        Ok(())
+    }
+
+    pub fn get_emergency_stop(&mut self) -> bool {
+        estop::is_active()
+    }
+
+    ///Drops every command and turns the motors off without ramping
+    fn cut_outputs(&mut self) -> Result<(), M::Error> {
+        self.speed = 0;
+        self.target = [0.0; 4];
+        self.deadman_deadline = None;
+        self.ramp.reset();
+        self.speed_control.reset();
+        self.duties = [0; 4];
+        self.outputs_cut = true;
+        self.driver.coast()?;
+        debug!("Fordonet nödstoppat!");
+        Ok(())
+    }
+    ///Sets max speed. If current speed is greater or less than (forwards or backwards) a new allowed speed will be set.
+    ///A low battery caps it further.
+    pub fn set_max_speed(&mut self, max: i32) -> Result<(), M::Error> {
+        //debug!("Sätter maxhastighet till {max}");
+        self.max_speed_setting = max.abs();
+        let max = match self.battery.speed_cap() {
+            Some(cap) => self.max_speed_setting.min(cap),
+            None => self.max_speed_setting,
+        };
+        self.maxspeed = max;
+        //Scale all wheels together so turns keep their radius
+        let largest = self.target.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        if largest > max as f32 {
+            let scale = max as f32 / largest;
+            self.target = self.target.map(|s| s * scale);
+            self.speed = self.speed.clamp(-max, max);
+            //Not through `drive`, a program move keeps its heading and distance goal within the new max speed
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    pub fn get_max_speed(&mut self) -> i32 {
+        self.maxspeed
+    }
+
+    ///Duty for every wheel at the given speeds, looked up in the duty curves
+    fn calculate_duties(&self, speeds: WheelSpeeds) -> WheelDuties {
+        let mut duties: WheelDuties = [0; 4];
+        for wheel in Wheel::ALL {
+            duties[wheel.index()] = self.curves.duty(wheel, speeds[wheel.index()]);
+        }
+        debug!("Calculated duties: {:?}", duties);
+        duties
+    }
+
+    /// Applies speed to the vehice.
+    pub fn set_speed(&mut self, mut speed: i32) -> Result<(), M::Error> {
+        if !estop::is_active() {
+            match speed {
+                1..=100 => {
+                    if speed > self.maxspeed {
+                        speed = self.maxspeed
+                    }
+                    self.speed = speed;
+                    self.forward(speed as f32)?;
+                }
+                -100..=-1 => {
+                    if speed < (self.maxspeed * -1) {
+                        speed = self.maxspeed * -1;
+                    }
+                    self.speed = speed;
+                    self.backwards(-speed as f32)?;
+                }
+                0 => self.stop_vehicle()?,
+                _ => {}
+            }
+            self.refresh_deadman();
+        }
+        Ok(())
+    }
+
+    //------Driving functions-------
+    /// Commands a signed speed for every wheel. The control loop ramps the wheels there.
+    /// Replaces any heading or distance goal of a program move.
+    fn drive(&mut self, speeds: WheelSpeeds) -> Result<(), M::Error> {
+        self.heading_goal = None;
+        self.distance_left = None;
+        self.move_done = false;
+        self.target = speeds;
+        self.tick()
+    }
+
+    /// Drives all wheels forward with the same speed
+    fn forward(&mut self, speed: f32) -> Result<(), M::Error> {
+        self.drive([speed; 4])
+    }
+
+    /// Drives all wheels backward with the same speed
+    fn backwards(&mut self, speed: f32) -> Result<(), M::Error> {
+        self.drive([-speed; 4])
+    }
+
+    //Fetch speed
+    pub fn get_speed(&mut self) -> i32 {
+        self.speed
+    }
+    ///Stops Vehicle completely. The wheels slow down at the deceleration limit.
+    pub fn stop_vehicle(&mut self) -> Result<(), M::Error> {
+        self.speed = 0;
+        self.drive([0.0; 4])?;
+        debug!("Fordonet stoppat!");
+        Ok(())
+    }
+
+    //---------------------- Twist ------------------------
+    ///Continuous skid-steer control. `linear` is forward speed and `angular` the turn rate, both -100..100.
+    ///Positive angular turns left. If a side would go past max speed both sides are scaled down together
+    ///so the curve radius is kept.
+    pub fn set_twist(&mut self, linear: f32, angular: f32) -> Result<(), M::Error> {
+        if !estop::is_active() {
+            let (left, right) = mix_twist(linear, angular, self.maxspeed as f32);
+            self.speed = ((left + right) / 2.0) as i32;
+            self.drive(sides(left, right))?;
+            self.refresh_deadman();
+        }
+        Ok(())
+    }
+
+    //---------------------- Joystick ------------------------
+    ///Analog stick control. `x` and `y` are -1..1, `throttle` 0..1. Shaped by the joystick config and
+    ///driven as a twist, so max speed and emergency stop apply as usual.
+    pub fn joystick_control(&mut self, x: f32, y: f32, throttle: f32) -> Result<(), M::Error> {
+        let (linear, angular) = self.joystick.to_twist(x, y, throttle);
+        self.set_twist(linear, angular)
+    }
+
+    //---------------------- Keyboardstyrning ------------------------
+    /*
+       Hur direction fungerar:
+        1
+      8 ^ 2
+      \ |/
+    7<- . -> 3
+      / |\
+     6  v 4
+        5
+       */
+    pub fn keyboard_control(
+        &mut self,
+        direction: i32,
+        state: bool,
+        speed: i32,
+    ) -> Result<(), M::Error> {
+        if !estop::is_active() {
+            if !state {
+                self.stop_vehicle()?;
+            } else {
+                let speed = speed as f32;
+                match direction {
+                    1 => self.forward(speed)?,
+                    2 => self.turn(speed / 2.0, speed, true, true)?,
+                    3 => self.turn(0.0, speed, true, true)?,
+                    4 => self.turn(speed / 2.0, speed, true, false)?,
+                    5 => self.backwards(speed)?,
+                    6 => self.turn(speed / 2.0, speed, false, false)?,
+                    7 => self.turn(0.0, speed, false, true)?,
+                    8 => self.turn(speed / 2.0, speed, false, true)?,
+                    _ => self.stop_vehicle()?,
+                }
+            }
+            self.refresh_deadman();
+        }
+        debug!("Exiting keyboard control...");
+        Ok(())
+    }
+
+    ///Turns with inner wheel speed `iw` and outer wheel speed `ow`
+    fn turn(&mut self, iw: f32, ow: f32, right: bool, fwd: bool) -> Result<(), M::Error> {
+        let speeds: WheelSpeeds = match (right, fwd) {
+            //Sväng: höger framåt
+            (true, true) => sides(ow, iw),
+            //Sväng: vänster framåt
+            (false, true) => sides(iw, ow),
+            //Sväng: höger bakåt... Vänstra hjulen bakåt
+            (true, false) => sides(-ow, -iw),
+            //Sväng: vänster bakåt... Högra hjulen bakåt
+            (false, false) => sides(-iw, -ow),
+        };
+        self.drive(speeds)
+    }
+    //---------------------------------------------
+    //--------------- INSTRUCTIONS ---------------
+    //Timed moves for block builder programs. They only start the motion and return how long it should run,
+    //the motion executor does the waiting without holding the controller. Speeds above max speed are capped
+    //and the time is stretched to match. With an IMU, `from` is the heading the move started from: rotations
+    //then end when the heading is reached (see `is_move_done`) and straight moves hold it. With encoders,
+    //straight moves end when the measured distance has been driven.
+
+    ///Starts rotating left, returns the time needed for X degrees
+    pub fn start_rotate_l(
+        &mut self,
+        degrees: f32,
+        speed: f32,
+        from: Option<f32>,
+    ) -> Result<Duration, M::Error> {
+        debug!("Roterar {} grader åt vänster", degrees);
+        let speed = self.program_speed(speed);
+        self.rotation(speed, true)?;
+        Ok(self.rotate_for(degrees, speed, from))
+    }
+    ///Starts rotating right, returns the time needed for X degrees
+    pub fn start_rotate_r(
+        &mut self,
+        degrees: f32,
+        speed: f32,
+        from: Option<f32>,
+    ) -> Result<Duration, M::Error> {
+        debug!("Roterar {} grader åt höger", degrees);
+        let speed = self.program_speed(speed);
+        self.rotation(speed, false)?;
+        Ok(self.rotate_for(-degrees, speed, from))
+    }
+    ///Time for a rotation of signed `degrees` (positive left). Closed on the heading it is only a time limit,
+    ///in case the wheels slip and the goal is never reached.
+    fn rotate_for(&mut self, degrees: f32, speed: f32, from: Option<f32>) -> Duration {
+        let time = self.calibration.rotate_time(degrees, speed);
+        if self.close_on_heading(from, |from| HeadingGoal::Rotate {
+            target: from + degrees,
+            speed,
+        }) {
+            time * 2 + Duration::from_secs(1)
+        } else {
+            time
+        }
+    }
+    ///Starts driving forward, returns the time needed for X meters
+    ///2,72
+    ///2,82
+    ///2,81
+    ///2,80
+    pub fn start_forward(
+        &mut self,
+        meters: f32,
+        speed: f32,
+        from: Option<f32>,
+    ) -> Result<Duration, M::Error> {
+        debug!("Kör {} meter framåt", meters);
+        let speed = self.program_speed(speed);
+        self.forward(speed)?;
+        self.close_on_heading(from, |heading| HeadingGoal::Hold { heading, speed });
+        Ok(self.drive_for(meters, speed))
+    }
+    ///Starts driving backward, returns the time needed for X meters
+    ///2,8
+    pub fn start_backward(
+        &mut self,
+        meters: f32,
+        speed: f32,
+        from: Option<f32>,
+    ) -> Result<Duration, M::Error> {
+        debug!("Kör {} meter bakåt", meters);
+        let speed = self.program_speed(speed);
+        self.backwards(speed)?;
+        self.close_on_heading(from, |heading| HeadingGoal::Hold {
+            heading,
+            speed: -speed,
+        });
+        Ok(self.drive_for(meters, speed))
+    }
+    ///Time for a straight move of `meters`. Closed on the encoders it is only a time limit, in case a wheel
+    ///is held and the distance is never reached.
+    fn drive_for(&mut self, meters: f32, speed: f32) -> Duration {
+        let time = self.calibration.drive_time(meters, speed);
+        if self.close_on_distance(meters) {
+            time * 2 + Duration::from_secs(1)
+        } else {
+            time
+        }
+    }
+    ///Starts driving along a circle with `radius` meters, returns the time needed for X degrees of it.
+    ///Positive degrees turn left. The outer side runs at `speed` and the inner side slower.
+    pub fn start_arc(
+        &mut self,
+        radius: f32,
+        degrees: f32,
+        speed: f32,
+    ) -> Result<Duration, M::Error> {
+        debug!(
+            "Kör {} grader längs en båge med radie {} m",
+            degrees, radius
+        );
+        let speed = self.program_speed(speed);
+        let inner = self.calibration.arc_inner_speed(radius, speed);
+        self.turn(inner, speed, degrees < 0.0, true)?;
+        Ok(self.calibration.arc_time(radius, degrees, speed))
+    }
+
+    ///Speed for a timed move, capped by max speed. Timed moves are not live control, so the deadman is off.
+    fn program_speed(&mut self, speed: f32) -> f32 {
+        self.deadman_deadline = None;
+        self.capped_speed(speed)
+    }
+
+    ///Speed a timed move asking for `speed` will actually run at
+    pub fn capped_speed(&self, speed: f32) -> f32 {
+        speed.clamp(1.0, self.maxspeed.max(1) as f32)
+    }
+    ///function for handling rotations.
+    /// 180 grader 2 sekunder. 90 grader 1 sekund Båda sidor!.
+    ///
+    fn rotation(&mut self, speed: f32, left: bool) -> Result<(), M::Error> {
+        if left {
+            self.drive(sides(-speed, speed))
+        } else {
+            self.drive(sides(speed, -speed))
+        }
+    }
+    //---------------------------------------------
+}
+
+///Skid-steer mixing of linear and angular speed into left and right side speeds, desaturated to `max`
+fn mix_twist(linear: f32, angular: f32, max: f32) -> (f32, f32) {
+    let left = linear - angular;
+    let right = linear + angular;
+    let largest = left.abs().max(right.abs());
+    if largest > max && largest > 0.0 {
+        let scale = max / largest;
+        (left * scale, right * scale)
+    } else {
+        (left, right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::BatteryConfig;
+    use std::convert::Infallible;
+
+    ///Motor driver that only keeps the last duties
+    #[derive(Default)]
+    struct Recorder {
+        duties: WheelDuties,
+    }
+
+    impl MotorDriver for Recorder {
+        type Error = Infallible;
+
+        fn set_duties(&mut self, duties: WheelDuties) -> Result<(), Infallible> {
+            self.duties = duties;
+            Ok(())
+        }
+
+        fn set_wheel(&mut self, wheel: Wheel, duty: i16) -> Result<(), Infallible> {
+            self.duties[wheel.index()] = duty;
+            Ok(())
+        }
+
+        fn brake(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn coast(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn enable(&mut self, _enabled: bool) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    ///Vehicle with encoders on every wheel, driving forward 1 m closed on the distance
+    fn moving_on_encoders() -> Vehicle<Recorder> {
+        let mut vehicle = Vehicle::new(
+            Recorder::default(),
+            DutyCurves::default(),
+            RampConfig::default(),
+        );
+        vehicle.update_encoders([Some(0); 4]);
+        vehicle.start_forward(1.0, 100.0, None).unwrap();
+        assert_eq!(vehicle.get_distance_left(), Some(1.0));
+        vehicle
+    }
+
+    #[test]
+    fn speed_control_needs_an_encoder() {
+        let mut vehicle = Vehicle::new(
+            Recorder::default(),
+            DutyCurves::default(),
+            RampConfig::default(),
+        );
+        let config = SpeedControlConfig {
+            enabled: true,
+            ..SpeedControlConfig::default()
+        };
+        assert!(vehicle.set_speed_control_config(config).is_err());
+        assert!(!vehicle.get_speed_control_config().enabled);
+        vehicle.set_encoders([true, true, false, false]);
+        assert!(vehicle.set_speed_control_config(config).is_ok());
+        vehicle.set_encoders([false; 4]);
+        assert!(!vehicle.get_speed_control_config().enabled);
+    }
+
+    #[test]
+    fn max_speed_during_a_move_keeps_the_distance_goal() {
+        let mut vehicle = moving_on_encoders();
+        vehicle.set_max_speed(40).unwrap();
+        assert_eq!(vehicle.target, [40.0; 4]);
+        assert_eq!(vehicle.get_distance_left(), Some(1.0));
+        assert!(!vehicle.is_move_done());
+    }
+
+    #[test]
+    fn battery_cap_during_a_move_keeps_the_distance_goal() {
+        let mut vehicle = moving_on_encoders();
+        vehicle
+            .set_battery_config(BatteryConfig {
+                enabled: true,
+                ..BatteryConfig::default()
+            })
+            .unwrap();
+        //6.8 V, halfway between the cutoff and the warning level
+        vehicle.update_battery(1.7).unwrap();
+        assert_eq!(vehicle.get_max_speed(), 70);
+        assert_eq!(vehicle.target, [70.0; 4]);
+        assert_eq!(vehicle.get_distance_left(), Some(1.0));
+        assert!(!vehicle.is_move_done());
+    }
+}
@@ -0,0 +1,322 @@
+use crate::safety::{Range, RangeSensor};
+use embedded_hal::i2c::{Error, ErrorKind, I2c};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+///Address at power on
+pub const DEFAULT_ADDRESS: u8 = 0x29;
+const MODEL_ID: u8 = 0xEE;
+
+const SYSRANGE_START: u8 = 0x00;
+const SYSTEM_SEQUENCE_CONFIG: u8 = 0x01;
+const SYSTEM_INTERRUPT_CONFIG_GPIO: u8 = 0x0A;
+const SYSTEM_INTERRUPT_CLEAR: u8 = 0x0B;
+const RESULT_INTERRUPT_STATUS: u8 = 0x13;
+const RESULT_RANGE_STATUS: u8 = 0x14;
+const FINAL_RANGE_CONFIG_MIN_COUNT_RATE_RTN_LIMIT: u8 = 0x44;
+const MSRC_CONFIG_CONTROL: u8 = 0x60;
+const GPIO_HV_MUX_ACTIVE_HIGH: u8 = 0x84;
+const VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV: u8 = 0x89;
+const GLOBAL_CONFIG_SPAD_ENABLES_REF_0: u8 = 0xB0;
+const GLOBAL_CONFIG_REF_EN_START_SELECT: u8 = 0xB6;
+const DYNAMIC_SPAD_NUM_REQUESTED_REF_SPAD: u8 = 0x4E;
+const DYNAMIC_SPAD_REF_EN_START_OFFSET: u8 = 0x4F;
+const IDENTIFICATION_MODEL_ID: u8 = 0xC0;
+
+///Readings at or above this (mm) mean nothing was in range
+const OUT_OF_RANGE: u16 = 8190;
+///Longest wait for the sensor during start up
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+///Tuning settings from ST's API (DefaultTuningSettings), written during start up
+const TUNING: [(u8, u8); 80] = [
+    (0xFF, 0x01),
+    (0x00, 0x00),
+    (0xFF, 0x00),
+    (0x09, 0x00),
+    (0x10, 0x00),
+    (0x11, 0x00),
+    (0x24, 0x01),
+    (0x25, 0xFF),
+    (0x75, 0x00),
+    (0xFF, 0x01),
+    (0x4E, 0x2C),
+    (0x48, 0x00),
+    (0x30, 0x20),
+    (0xFF, 0x00),
+    (0x30, 0x09),
+    (0x54, 0x00),
+    (0x31, 0x04),
+    (0x32, 0x03),
+    (0x40, 0x83),
+    (0x46, 0x25),
+    (0x60, 0x00),
+    (0x27, 0x00),
+    (0x50, 0x06),
+    (0x51, 0x00),
+    (0x52, 0x96),
+    (0x56, 0x08),
+    (0x57, 0x30),
+    (0x61, 0x00),
+    (0x62, 0x00),
+    (0x64, 0x00),
+    (0x65, 0x00),
+    (0x66, 0xA0),
+    (0xFF, 0x01),
+    (0x22, 0x32),
+    (0x47, 0x14),
+    (0x49, 0xFF),
+    (0x4A, 0x00),
+    (0xFF, 0x00),
+    (0x7A, 0x0A),
+    (0x7B, 0x00),
+    (0x78, 0x21),
+    (0xFF, 0x01),
+    (0x23, 0x34),
+    (0x42, 0x00),
+    (0x44, 0xFF),
+    (0x45, 0x26),
+    (0x46, 0x05),
+    (0x40, 0x40),
+    (0x0E, 0x06),
+    (0x20, 0x1A),
+    (0x43, 0x40),
+    (0xFF, 0x00),
+    (0x34, 0x03),
+    (0x35, 0x44),
+    (0xFF, 0x01),
+    (0x31, 0x04),
+    (0x4B, 0x09),
+    (0x4C, 0x05),
+    (0x4D, 0x04),
+    (0xFF, 0x00),
+    (0x44, 0x00),
+    (0x45, 0x20),
+    (0x47, 0x08),
+    (0x48, 0x28),
+    (0x67, 0x00),
+    (0x70, 0x04),
+    (0x71, 0x01),
+    (0x72, 0xFE),
+    (0x76, 0x00),
+    (0x77, 0x00),
+    (0xFF, 0x01),
+    (0x0D, 0x01),
+    (0xFF, 0x00),
+    (0x80, 0x01),
+    (0x01, 0xF8),
+    (0xFF, 0x01),
+    (0x8E, 0x01),
+    (0x00, 0x01),
+    (0xFF, 0x00),
+    (0x80, 0x00),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeError {
+    Write(ErrorKind),
+    Read(ErrorKind),
+    ///Something else answered on the address, with its model id
+    WrongDevice(u8),
+    ///The sensor did not finish a start up step in time
+    Timeout,
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RangeError::Write(kind) => {
+                write!(f, "I2C-fel vid skrivning till avståndssensor: {:?}", kind)
+            }
+            RangeError::Read(kind) => {
+                write!(f, "I2C-fel vid läsning av avståndssensor: {:?}", kind)
+            }
+            RangeError::WrongDevice(id) => write!(f, "Okänd avståndssensor, modell {:02x}", id),
+            RangeError::Timeout => write!(f, "Avståndssensorn svarar inte"),
+        }
+    }
+}
+
+impl std::error::Error for RangeError {}
+
+///ST VL53L0X time-of-flight distance sensor, up to about 2 m. Runs back-to-back measurements (about 30 Hz)
+///once started, `read` picks up the latest one without waiting.
+pub struct Vl53l0x<I2C> {
+    i2c: I2C,
+    address: u8,
+    stop_variable: u8,
+}
+
+impl<I2C: I2c> Vl53l0x<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            stop_variable: 0,
+        }
+    }
+
+    ///Start up sequence from ST's API (data init, static init and reference calibration), then starts ranging
+    pub fn init(&mut self) -> Result<(), RangeError> {
+        let id = self.read_register(IDENTIFICATION_MODEL_ID)?;
+        if id != MODEL_ID {
+            return Err(RangeError::WrongDevice(id));
+        }
+        //2V8 I/O and standard mode I2C
+        let pad = self.read_register(VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV)?;
+        self.write_register(VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV, pad | 0x01)?;
+        self.write_register(0x88, 0x00)?;
+        self.write_registers(&[(0x80, 0x01), (0xFF, 0x01), (0x00, 0x00)])?;
+        self.stop_variable = self.read_register(0x91)?;
+        self.write_registers(&[(0x00, 0x01), (0xFF, 0x00), (0x80, 0x00)])?;
+        //No MSRC and pre-range signal rate limit checks, 0.25 MCPS final range limit (9.7 fixed point)
+        let msrc = self.read_register(MSRC_CONFIG_CONTROL)?;
+        self.write_register(MSRC_CONFIG_CONTROL, msrc | 0x12)?;
+        self.write_buf(&[FINAL_RANGE_CONFIG_MIN_COUNT_RATE_RTN_LIMIT, 0x00, 0x20])?;
+        self.write_register(SYSTEM_SEQUENCE_CONFIG, 0xFF)?;
+
+        self.init_reference_spads()?;
+        self.write_registers(&TUNING)?;
+
+        //Interrupt on new sample ready
+        self.write_register(SYSTEM_INTERRUPT_CONFIG_GPIO, 0x04)?;
+        let mux = self.read_register(GPIO_HV_MUX_ACTIVE_HIGH)?;
+        self.write_register(GPIO_HV_MUX_ACTIVE_HIGH, mux & !0x10)?;
+        self.write_register(SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+
+        //VHV and phase calibration
+        self.write_register(SYSTEM_SEQUENCE_CONFIG, 0x01)?;
+        self.single_ref_calibration(0x40)?;
+        self.write_register(SYSTEM_SEQUENCE_CONFIG, 0x02)?;
+        self.single_ref_calibration(0x00)?;
+        self.write_register(SYSTEM_SEQUENCE_CONFIG, 0xE8)?;
+
+        self.start_continuous()
+    }
+
+    ///Enables the reference SPADs the factory calibration asks for
+    fn init_reference_spads(&mut self) -> Result<(), RangeError> {
+        let (count, aperture) = self.spad_info()?;
+        let mut map = [0u8; 6];
+        self.read_buf(GLOBAL_CONFIG_SPAD_ENABLES_REF_0, &mut map)?;
+        self.write_registers(&[
+            (0xFF, 0x01),
+            (DYNAMIC_SPAD_REF_EN_START_OFFSET, 0x00),
+            (DYNAMIC_SPAD_NUM_REQUESTED_REF_SPAD, 0x2C),
+            (0xFF, 0x00),
+            (GLOBAL_CONFIG_REF_EN_START_SELECT, 0xB4),
+        ])?;
+        //Aperture SPADs start at 12
+        let first = if aperture { 12 } else { 0 };
+        let mut enabled = 0;
+        for i in 0..48 {
+            let bit = 1 << (i % 8);
+            if i < first || enabled == count {
+                map[i / 8] &= !bit;
+            } else if map[i / 8] & bit != 0 {
+                enabled += 1;
+            }
+        }
+        let mut buf = [0u8; 7];
+        buf[0] = GLOBAL_CONFIG_SPAD_ENABLES_REF_0;
+        buf[1..].copy_from_slice(&map);
+        self.write_buf(&buf)
+    }
+
+    ///Number of reference SPADs and if they are of the aperture type
+    fn spad_info(&mut self) -> Result<(usize, bool), RangeError> {
+        self.write_registers(&[(0x80, 0x01), (0xFF, 0x01), (0x00, 0x00), (0xFF, 0x06)])?;
+        let value = self.read_register(0x83)?;
+        self.write_register(0x83, value | 0x04)?;
+        self.write_registers(&[
+            (0xFF, 0x07),
+            (0x81, 0x01),
+            (0x80, 0x01),
+            (0x94, 0x6B),
+            (0x83, 0x00),
+        ])?;
+        let start = Instant::now();
+        while self.read_register(0x83)? == 0x00 {
+            if start.elapsed() > TIMEOUT {
+                return Err(RangeError::Timeout);
+            }
+        }
+        self.write_register(0x83, 0x01)?;
+        let info = self.read_register(0x92)?;
+        self.write_registers(&[(0x81, 0x00), (0xFF, 0x06)])?;
+        let value = self.read_register(0x83)?;
+        self.write_register(0x83, value & !0x04)?;
+        self.write_registers(&[(0xFF, 0x01), (0x00, 0x01), (0xFF, 0x00), (0x80, 0x00)])?;
+        Ok(((info & 0x7F) as usize, info & 0x80 != 0))
+    }
+
+    fn single_ref_calibration(&mut self, vhv_init: u8) -> Result<(), RangeError> {
+        self.write_register(SYSRANGE_START, 0x01 | vhv_init)?;
+        let start = Instant::now();
+        while self.read_register(RESULT_INTERRUPT_STATUS)? & 0x07 == 0 {
+            if start.elapsed() > TIMEOUT {
+                return Err(RangeError::Timeout);
+            }
+        }
+        self.write_register(SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+        self.write_register(SYSRANGE_START, 0x00)
+    }
+
+    ///Back-to-back ranging, as fast as the timing budget allows
+    fn start_continuous(&mut self) -> Result<(), RangeError> {
+        self.write_registers(&[(0x80, 0x01), (0xFF, 0x01), (0x00, 0x00)])?;
+        self.write_register(0x91, self.stop_variable)?;
+        self.write_registers(&[(0x00, 0x01), (0xFF, 0x00), (0x80, 0x00)])?;
+        self.write_register(SYSRANGE_START, 0x02)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), RangeError> {
+        self.write_buf(&[register, value])
+    }
+
+    fn write_registers(&mut self, values: &[(u8, u8)]) -> Result<(), RangeError> {
+        for (register, value) in values {
+            self.write_register(*register, *value)?;
+        }
+        Ok(())
+    }
+
+    fn write_buf(&mut self, buf: &[u8]) -> Result<(), RangeError> {
+        self.i2c
+            .write(self.address, buf)
+            .map_err(|e| RangeError::Write(e.kind()))
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, RangeError> {
+        let mut data = [0];
+        self.read_buf(register, &mut data)?;
+        Ok(data[0])
+    }
+
+    fn read_buf(&mut self, register: u8, data: &mut [u8]) -> Result<(), RangeError> {
+        self.i2c
+            .write_read(self.address, &[register], data)
+            .map_err(|e| RangeError::Read(e.kind()))
+    }
+}
+
+impl<I2C: I2c> RangeSensor for Vl53l0x<I2C> {
+    type Error = RangeError;
+
+    fn read(&mut self) -> Result<Option<Range>, RangeError> {
+        if self.read_register(RESULT_INTERRUPT_STATUS)? & 0x07 == 0 {
+            return Ok(None);
+        }
+        let mut data = [0u8; 2];
+        self.read_buf(RESULT_RANGE_STATUS + 10, &mut data)?;
+        self.write_register(SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+        let millimeters = u16::from_be_bytes(data);
+        Ok(Some(if millimeters >= OUT_OF_RANGE {
+            Range::Clear
+        } else {
+            Range::Distance(millimeters as f32 / 1000.0)
+        }))
+    }
+}